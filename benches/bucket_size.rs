@@ -0,0 +1,48 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use n_body::octree::OcTree;
+use n_body::softening::Softening;
+use n_body::solver::barnes_hut_force;
+use n_body::vector::Vector3;
+
+mod common;
+use common::{random_bodies, BOUNDARY};
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let boundary = BOUNDARY;
+    let bodies = random_bodies(20_000);
+
+    let mut build = c.benchmark_group("octree build by bucket size");
+    for &bucket_size in [1, 4, 8, 16, 32].iter() {
+        build.bench_with_input(
+            BenchmarkId::from_parameter(bucket_size),
+            &bucket_size,
+            |b, &bucket_size| {
+                b.iter(|| {
+                    let mut ot = OcTree::with_bucket_size(boundary, bucket_size);
+                    for body in bodies.iter() {
+                        ot.insert(black_box(*body)).ok();
+                    }
+                    ot
+                })
+            },
+        );
+    }
+    build.finish();
+
+    let mut walk = c.benchmark_group("octree walk by bucket size");
+    walk.sample_size(10);
+    for &bucket_size in [1, 4, 8, 16, 32].iter() {
+        let ot = OcTree::build_parallel(boundary, bucket_size, &bodies).unwrap();
+        walk.bench_with_input(BenchmarkId::from_parameter(bucket_size), &ot, |b, ot| {
+            b.iter(|| {
+                bodies.iter().fold(Vector3::zero(), |f, body| {
+                    f + barnes_hut_force(0.8, Softening::None, body, ot)
+                })
+            })
+        });
+    }
+    walk.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+//! Bodies shared by the benches
+use n_body::{body::Body, cube::Cube, vector::Vector3};
+use rand::Rng;
+
+/// Root cube holding every body of `random_bodies`
+pub const BOUNDARY: Cube = Cube {
+    pos: Vector3 {
+        x: -50.0,
+        y: -50.0,
+        z: -50.0,
+    },
+    size: 100.0,
+};
+
+/// `n` bodies at rest, spread uniformly over `BOUNDARY`, with masses from 1 to 100
+pub fn random_bodies(n: u32) -> Vec<Body> {
+    let mut rng = rand::thread_rng();
+    (0..n)
+        .map(|i| {
+            Body::new(
+                i,
+                Vector3::new(
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                ),
+                Vector3::zero(),
+                rng.gen_range(1.0..100.0),
+            )
+        })
+        .collect()
+}
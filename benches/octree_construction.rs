@@ -0,0 +1,35 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use n_body::linear_octree::LinearOcTree;
+use n_body::octree::OcTree;
+
+mod common;
+use common::{random_bodies, BOUNDARY};
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("octree construction");
+    group.sample_size(10);
+    let boundary = BOUNDARY;
+    for &n in [100_000, 1_000_000].iter() {
+        let bodies = random_bodies(n);
+
+        group.bench_with_input(BenchmarkId::new("insertion", n), &bodies, |b, bodies| {
+            b.iter(|| {
+                let mut ot = OcTree::new(boundary);
+                for body in bodies.iter() {
+                    ot.insert(black_box(*body)).ok();
+                }
+                ot
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", n), &bodies, |b, bodies| {
+            b.iter(|| OcTree::build_parallel(boundary, 1, black_box(bodies)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("linear", n), &bodies, |b, bodies| {
+            b.iter(|| LinearOcTree::new(boundary, 1, black_box(bodies)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
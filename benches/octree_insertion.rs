@@ -14,13 +14,12 @@ fn criterion_benchmark(c: &mut Criterion) {
                 size: 100.0,
             });
             for i in 0..1000 {
-                let b = Body {
-                    id: i,
-                    pos: Vector3::new(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 0.0),
-                    vel: Vector3::zero(),
-                    acc: Vector3::zero(),
-                    mass: rng.gen_range(1.0..100.0),
-                };
+                let b = Body::new(
+                    i,
+                    Vector3::new(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), 0.0),
+                    Vector3::zero(),
+                    rng.gen_range(1.0..100.0),
+                );
                 qt.insert(black_box(b)).ok();
             }
         })
@@ -0,0 +1,56 @@
+//! Sweep Barnes-Hut theta over a random disk and pick the cheapest theta
+//! meeting a required accuracy.
+//!
+//! Usage: cargo run --release --example theta_sweep [bodies] [max 99th percentile error]
+use n_body::{accuracy::theta_sweep, body::Body, softening::Softening, vector::Vector3};
+use rand::Rng;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let n: u32 = args.next().map_or(2000, |a| a.parse().expect("bodies"));
+    let required: f64 = args.next().map_or(1e-2, |a| a.parse().expect("error"));
+
+    let mut rng = rand::thread_rng();
+    let bodies: Vec<Body> = (0..n)
+        .map(|i| {
+            Body::new(
+                i,
+                Vector3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-0.1..0.1),
+                ) * rng.gen_range(0.0..1000.0),
+                Vector3::zero(),
+                rng.gen_range(1.0..100.0),
+            )
+        })
+        .collect();
+
+    let thetas: Vec<f64> = (1..=15).map(|i| i as f64 * 0.1).collect();
+    let reports =
+        theta_sweep(&bodies, Softening::None, &thetas).expect("Bodies must have finite positions");
+
+    println!(
+        "{:>6} {:>12} {:>12} {:>12} {:>12} {:>14}",
+        "theta", "rms", "median", "p99", "max", "interactions"
+    );
+    for r in reports.iter() {
+        println!(
+            "{:>6.2} {:>12.3e} {:>12.3e} {:>12.3e} {:>12.3e} {:>14}",
+            r.theta, r.rms, r.median, r.p99, r.max, r.tree_interactions
+        );
+    }
+    println!(
+        "direct summation: {} interactions",
+        reports[0].direct_interactions
+    );
+
+    match reports
+        .iter()
+        .filter(|r| r.p99 <= required)
+        .min_by_key(|r| r.tree_interactions)
+    {
+        Some(r) => println!("cheapest theta with p99 <= {:e}: {:.2}", required, r.theta),
+        None => println!("no theta reaches p99 <= {:e}", required),
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    body::Body,
+    octree::{OcTree, OcTreeError},
+    softening::Softening,
+    solver::{barnes_hut_forces, barnes_hut_interactions, direct_forces},
+    vector::Vector3,
+};
+
+/// Relative error of Barnes-Hut accelerations against direct summation
+#[derive(Debug, Copy, Clone)]
+pub struct AccuracyReport {
+    pub theta: f64,
+    pub rms: f64,
+    pub median: f64,
+    pub p99: f64,
+    pub max: f64,
+    /// Bodies the errors are over, leaving out those with no exact force
+    pub compared: usize,
+    /// Body and node interactions of the tree walk, summed over all bodies
+    pub tree_interactions: usize,
+    /// Interactions of direct summation, N(N-1)
+    pub direct_interactions: usize,
+}
+
+/// Nearest-rank percentile of sorted values, zero when there are none
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Compare Barnes-Hut accelerations at `theta` with exact ones for `bodies`,
+/// both softened by `softening`
+pub fn force_accuracy(
+    bodies: &[Body],
+    softening: Softening,
+    theta: f64,
+) -> Result<AccuracyReport, OcTreeError> {
+    Ok(theta_sweep(bodies, softening, &[theta])?[0])
+}
+
+/// Reports for every theta, building the tree and the exact forces once
+pub fn theta_sweep(
+    bodies: &[Body],
+    softening: Softening,
+    thetas: &[f64],
+) -> Result<Vec<AccuracyReport>, OcTreeError> {
+    let ot = OcTree::from_bodies(bodies, 1)?;
+    let exact = direct_forces(softening, bodies);
+    Ok(thetas
+        .iter()
+        .map(|&theta| report(bodies, &ot, &exact, softening, theta))
+        .collect())
+}
+
+fn report(
+    bodies: &[Body],
+    ot: &OcTree,
+    exact: &[Vector3],
+    softening: Softening,
+    theta: f64,
+) -> AccuracyReport {
+    // A relative error means nothing for a body with no force on it
+    let mut errors: Vec<f64> = barnes_hut_forces(theta, softening, bodies, ot)
+        .iter()
+        .zip(exact.iter())
+        .filter(|(_, f)| f.dot(**f) > 0.0)
+        .map(|(a, f)| {
+            let diff = *a - *f;
+            (diff.dot(diff) / f.dot(*f)).sqrt()
+        })
+        .collect();
+    errors.sort_by(f64::total_cmp);
+
+    let n = bodies.len();
+    AccuracyReport {
+        theta,
+        rms: (errors.iter().map(|e| e * e).sum::<f64>() / errors.len().max(1) as f64).sqrt(),
+        median: percentile(&errors, 50.0),
+        p99: percentile(&errors, 99.0),
+        max: percentile(&errors, 100.0),
+        compared: errors.len(),
+        tree_interactions: bodies
+            .iter()
+            .map(|b| barnes_hut_interactions(theta, b, ot))
+            .sum(),
+        direct_interactions: n * n.saturating_sub(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_theta_sweep() {
+        let bodies = fixtures::random_bodies(500, 10.0, 2);
+        let reports = theta_sweep(&bodies, Softening::None, &[0.0, 0.5, 1.0]).unwrap();
+
+        assert!(reports[0].max < 1e-9);
+        assert_eq!(reports[0].tree_interactions, reports[0].direct_interactions);
+        for pair in reports.windows(2) {
+            assert!(pair[0].rms < pair[1].rms);
+            assert!(pair[0].tree_interactions > pair[1].tree_interactions);
+        }
+        for r in reports.iter() {
+            assert!(r.median <= r.p99 && r.p99 <= r.max);
+            assert_eq!(r.compared, 500);
+        }
+
+        // No body feels a force, or there are no bodies at all
+        let report = force_accuracy(&bodies[..1], Softening::None, 0.5).unwrap();
+        assert_eq!((report.compared, report.max), (0, 0.0));
+        let report = force_accuracy(&[], Softening::None, 0.5).unwrap();
+        assert_eq!((report.compared, report.direct_interactions), (0, 0));
+    }
+}
@@ -7,4 +7,24 @@ pub struct Body {
     pub vel: Vector3,
     pub acc: Vector3,
     pub mass: f64,
+    /// Own softening length, the simulation's softening applies when None
+    pub softening: Option<f64>,
+    /// System the body came from, set by `scenario::Merger`, zero otherwise
+    pub origin: u32,
+}
+
+impl Body {
+    /// Body with no acceleration yet, softened like the simulation and from
+    /// system zero
+    pub fn new(id: u32, pos: Vector3, vel: Vector3, mass: f64) -> Body {
+        Body {
+            id,
+            pos,
+            vel,
+            acc: Vector3::zero(),
+            mass,
+            softening: None,
+            origin: 0,
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use crate::vector::Vector3;
+use crate::{body::Body, vector::Vector3};
 use Region::*;
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct Cube {
@@ -7,7 +7,7 @@ pub struct Cube {
     pub size: f64,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum Region {
     TNE,
     TSE,
@@ -19,7 +19,62 @@ pub enum Region {
     BNW,
 }
 
+impl Region {
+    pub const ALL: [Region; 8] = [TNE, TSE, TSW, TNW, BNE, BSE, BSW, BNW];
+}
+
 impl Cube {
+    /// Cube centered on the origin, large enough for every body
+    pub fn bounding(bodies: &[Body]) -> Cube {
+        Cube::bounding_around(bodies, Vector3::zero())
+    }
+
+    /// Cube centered on `center`, large enough for every body on all three axes
+    pub fn bounding_around(bodies: &[Body], center: Vector3) -> Cube {
+        let max_dist: f64 = bodies.iter().fold(0.0, |a, b| {
+            let d = b.pos - center;
+            a.max(d.x.abs()).max(d.y.abs()).max(d.z.abs())
+        });
+        // Pad by a few ulps so rounding in `pos + size` never excludes the farthest body
+        let scale = max_dist + center.x.abs().max(center.y.abs()).max(center.z.abs());
+        let half = max_dist + scale * 4.0 * f64::EPSILON;
+        Cube {
+            pos: center - Vector3::new(half, half, half),
+            size: half * 2.0,
+        }
+    }
+
+    /// Double the cube towards `p` until it contains it, None if `p` is not finite
+    pub fn grow_to(&self, p: &Vector3) -> Option<Cube> {
+        if !(p.x.is_finite() && p.y.is_finite() && p.z.is_finite()) {
+            return None;
+        }
+        let mut c = *self;
+        if c.contains(p) {
+            return Some(c);
+        }
+        if c.size <= 0.0 {
+            c.size = 1.0;
+        }
+        while !c.contains(p) {
+            if p.x < c.pos.x {
+                c.pos.x -= c.size;
+            }
+            if p.y < c.pos.y {
+                c.pos.y -= c.size;
+            }
+            if p.z < c.pos.z {
+                c.pos.z -= c.size;
+            }
+            c.size *= 2.0;
+        }
+        Some(c)
+    }
+
+    pub fn center(&self) -> Vector3 {
+        self.pos + Vector3::new(self.size, self.size, self.size) * 0.5
+    }
+
     pub fn contains(&self, p: &Vector3) -> bool {
         let s = &self.pos;
         p.x >= s.x
@@ -124,6 +179,28 @@ mod tests {
         assert!(!c.contains(&p2));
     }
 
+    #[test]
+    fn test_bounding() {
+        let bodies: Vec<Body> = [
+            Vector3::new(1.0, -2.0, 9.0),
+            Vector3::new(3.0, 4.0, -1.0),
+            Vector3::new(-2.0, 0.5, 2.0),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, p)| Body::new(i as u32, *p, Vector3::zero(), 1.0))
+        .collect();
+        let around_origin = Cube::bounding(&bodies);
+        assert!((around_origin.size - 18.0).abs() < 1e-12);
+        let around_centroid =
+            Cube::bounding_around(&bodies, Vector3::new(2.0 / 3.0, 2.5 / 3.0, 10.0 / 3.0));
+        assert!(around_centroid.size < around_origin.size);
+        for b in bodies.iter() {
+            assert!(around_origin.contains(&b.pos));
+            assert!(around_centroid.contains(&b.pos));
+        }
+    }
+
     #[test]
     fn test_region() {
         let c = Cube {
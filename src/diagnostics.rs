@@ -0,0 +1,379 @@
+//! Conserved quantities of a set of bodies, to check how well a run keeps them.
+use rayon::prelude::*;
+
+use crate::{
+    body::Body,
+    octree::{OcTree, OcTreeError},
+    physics_helper::calc_potential,
+    softening::Softening,
+    solver::barnes_hut_potential,
+    vector::Vector3,
+};
+
+/// How potential energy is summed
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum PotentialMethod {
+    /// Every pair, O(N²)
+    Exact,
+    /// Barnes-Hut walk of a tree built for the purpose, O(N log N)
+    Tree { theta: f64 },
+}
+
+/// Conserved quantities at one point in time
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Diagnostics {
+    pub time: f64,
+    pub kinetic: f64,
+    pub potential: f64,
+    pub momentum: Vector3,
+    /// About the origin
+    pub angular_momentum: Vector3,
+    /// None for a system without mass
+    pub center_of_mass: Option<Vector3>,
+}
+
+impl Diagnostics {
+    pub fn energy(&self) -> f64 {
+        self.kinetic + self.potential
+    }
+}
+
+/// Change of the conserved quantities since the first record, absolute where
+/// the scale it is relative to is zero
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Drift {
+    pub time: f64,
+    /// Relative to the magnitude of the first total energy
+    pub energy: f64,
+    /// Relative to the summed magnitudes of the first momenta of the bodies
+    pub momentum: f64,
+    /// Relative to the summed magnitudes of the first angular momenta of the bodies
+    pub angular_momentum: f64,
+}
+
+/// Time series of diagnostics, recorded by `Simulation::update` when set
+pub struct DiagnosticsLog {
+    pub method: PotentialMethod,
+    /// Steps `Simulation::update` takes from one record to the next, 1
+    /// records after every step
+    pub interval: usize,
+    pub records: Vec<Diagnostics>,
+    /// Steps taken since the last record
+    steps: usize,
+    /// Scales of momentum and angular momentum drifts
+    momentum_scale: f64,
+    angular_momentum_scale: f64,
+}
+
+impl DiagnosticsLog {
+    pub fn new(method: PotentialMethod) -> Self {
+        DiagnosticsLog {
+            method,
+            interval: 1,
+            records: Vec::new(),
+            steps: 0,
+            momentum_scale: 0.0,
+            angular_momentum_scale: 0.0,
+        }
+    }
+
+    /// Record diagnostics of `bodies` at `time`
+    pub fn record(
+        &mut self,
+        bodies: &[Body],
+        time: f64,
+        g: f64,
+        softening: Softening,
+    ) -> Result<(), OcTreeError> {
+        self.record_with_tree(bodies, time, g, softening, None)
+    }
+
+    /// Record diagnostics like `record`, see `measure_with_tree`
+    pub fn record_with_tree(
+        &mut self,
+        bodies: &[Body],
+        time: f64,
+        g: f64,
+        softening: Softening,
+        ot: Option<&OcTree>,
+    ) -> Result<(), OcTreeError> {
+        if self.records.is_empty() {
+            self.momentum_scale = bodies
+                .iter()
+                .map(|b| momentum_of(b).dot(momentum_of(b)).sqrt())
+                .sum();
+            self.angular_momentum_scale = bodies
+                .iter()
+                .map(|b| {
+                    let l = angular_momentum_of(b);
+                    l.dot(l).sqrt()
+                })
+                .sum();
+        }
+        self.records.push(measure_with_tree(
+            bodies,
+            time,
+            g,
+            softening,
+            self.method,
+            ot,
+        )?);
+        self.steps = 0;
+        Ok(())
+    }
+
+    /// Count a step, true once `interval` steps were taken since the last record
+    pub(crate) fn step(&mut self) -> bool {
+        self.steps += 1;
+        self.steps >= self.interval
+    }
+
+    /// Drift of a record since the first one
+    pub fn drift(&self, record: &Diagnostics) -> Drift {
+        let first = &self.records[0];
+        let length = |d: Vector3| d.dot(d).sqrt();
+        Drift {
+            time: record.time,
+            energy: relative(
+                (record.energy() - first.energy()).abs(),
+                first.energy().abs(),
+            ),
+            momentum: relative(
+                length(record.momentum - first.momentum),
+                self.momentum_scale,
+            ),
+            angular_momentum: relative(
+                length(record.angular_momentum - first.angular_momentum),
+                self.angular_momentum_scale,
+            ),
+        }
+    }
+
+    /// Drift of every record
+    pub fn drifts(&self) -> Vec<Drift> {
+        self.records.iter().map(|r| self.drift(r)).collect()
+    }
+
+    pub fn last_drift(&self) -> Option<Drift> {
+        self.records.last().map(|r| self.drift(r))
+    }
+}
+
+/// `change` relative to `scale`, or absolute when there is no scale
+pub(crate) fn relative(change: f64, scale: f64) -> f64 {
+    if scale > 0.0 {
+        change / scale
+    } else {
+        change
+    }
+}
+
+fn momentum_of(b: &Body) -> Vector3 {
+    b.vel * b.mass
+}
+
+fn angular_momentum_of(b: &Body) -> Vector3 {
+    b.pos.cross(b.vel) * b.mass
+}
+
+pub fn kinetic_energy(bodies: &[Body]) -> f64 {
+    bodies.iter().map(|b| 0.5 * b.mass * b.vel.dot(b.vel)).sum()
+}
+
+/// Potential energy of every pair, summed directly
+pub fn potential_energy(bodies: &[Body], g: f64, softening: Softening) -> f64 {
+    let potential: f64 = bodies
+        .par_iter()
+        .enumerate()
+        .map(|(i, b1)| {
+            bodies[i + 1..]
+                .iter()
+                .map(|b2| calc_potential(b1, b2, softening))
+                .sum::<f64>()
+        })
+        .sum();
+    potential * g
+}
+
+/// Potential energy from a Barnes-Hut walk of `ot` for every body
+pub fn tree_potential_energy(
+    bodies: &[Body],
+    ot: &OcTree,
+    theta: f64,
+    g: f64,
+    softening: Softening,
+) -> f64 {
+    // Every pair is counted from both ends
+    let potential: f64 = bodies
+        .par_iter()
+        .map(|b| barnes_hut_potential(theta, softening, b, ot))
+        .sum();
+    potential * g * 0.5
+}
+
+pub fn momentum(bodies: &[Body]) -> Vector3 {
+    bodies
+        .iter()
+        .fold(Vector3::zero(), |p, b| p + momentum_of(b))
+}
+
+/// Angular momentum about the origin
+pub fn angular_momentum(bodies: &[Body]) -> Vector3 {
+    bodies
+        .iter()
+        .fold(Vector3::zero(), |l, b| l + angular_momentum_of(b))
+}
+
+/// Center of mass, None if there are no bodies
+pub fn center_of_mass(bodies: &[Body]) -> Option<Vector3> {
+    weighted_center(bodies.iter())
+}
+
+/// Center of mass of the bodies that came from system `origin`, None if there
+/// are none
+pub fn center_of_mass_of(bodies: &[Body], origin: u32) -> Option<Vector3> {
+    weighted_center(bodies.iter().filter(|b| b.origin == origin))
+}
+
+fn weighted_center<'a>(bodies: impl Iterator<Item = &'a Body>) -> Option<Vector3> {
+    let (mass, weighted) = bodies.fold((0.0, Vector3::zero()), |(m, w), b| {
+        (m + b.mass, w + b.pos * b.mass)
+    });
+    if mass > 0.0 {
+        Some(weighted / mass)
+    } else {
+        None
+    }
+}
+
+/// All diagnostics of `bodies` at `time`
+pub fn measure(
+    bodies: &[Body],
+    time: f64,
+    g: f64,
+    softening: Softening,
+    method: PotentialMethod,
+) -> Result<Diagnostics, OcTreeError> {
+    measure_with_tree(bodies, time, g, softening, method, None)
+}
+
+/// All diagnostics like `measure`, walking `ot`, a tree of `bodies`, for
+/// `PotentialMethod::Tree` instead of building one when given
+pub fn measure_with_tree(
+    bodies: &[Body],
+    time: f64,
+    g: f64,
+    softening: Softening,
+    method: PotentialMethod,
+    ot: Option<&OcTree>,
+) -> Result<Diagnostics, OcTreeError> {
+    let potential = match (method, ot) {
+        (PotentialMethod::Exact, _) => potential_energy(bodies, g, softening),
+        (PotentialMethod::Tree { theta }, Some(ot)) => {
+            tree_potential_energy(bodies, ot, theta, g, softening)
+        }
+        (PotentialMethod::Tree { theta }, None) => {
+            let ot = OcTree::from_bodies(bodies, 1)?;
+            tree_potential_energy(bodies, &ot, theta, g, softening)
+        }
+    };
+    Ok(Diagnostics {
+        time,
+        kinetic: kinetic_energy(bodies),
+        potential,
+        momentum: momentum(bodies),
+        angular_momentum: angular_momentum(bodies),
+        center_of_mass: center_of_mass(bodies),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_diagnostics() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut bodies = fixtures::random_bodies(500, 10.0, 7);
+        for b in bodies.iter_mut() {
+            b.vel = Vector3::new(rng.gen_range(-1.0..1.0), 0.0, 0.0);
+        }
+        let softening = Softening::Plummer(0.1);
+        let exact = measure(&bodies, 0.0, 1.0, softening, PotentialMethod::Exact).unwrap();
+        let tree = measure(
+            &bodies,
+            0.0,
+            1.0,
+            softening,
+            PotentialMethod::Tree { theta: 0.0 },
+        )
+        .unwrap();
+        assert!(((tree.potential - exact.potential) / exact.potential).abs() < 1e-12);
+        let approx = measure(
+            &bodies,
+            0.0,
+            1.0,
+            softening,
+            PotentialMethod::Tree { theta: 0.5 },
+        )
+        .unwrap();
+        assert!(((approx.potential - exact.potential) / exact.potential).abs() < 1e-3);
+        // A tree of the bodies is walked as given
+        let ot = OcTree::from_bodies(&bodies, 1).unwrap();
+        let method = PotentialMethod::Tree { theta: 0.5 };
+        let given = measure_with_tree(&bodies, 0.0, 1.0, softening, method, Some(&ot)).unwrap();
+        assert_eq!(given, approx);
+
+        // Motion along x has no angular momentum about the x axis
+        assert_eq!(exact.angular_momentum.x, 0.0);
+        let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+        let com = exact.center_of_mass.unwrap() * total_mass;
+        let weighted = bodies
+            .iter()
+            .fold(Vector3::zero(), |w, b| w + b.pos * b.mass);
+        let d = com - weighted;
+        assert!(d.dot(d).sqrt() < 1e-9);
+        // Every body comes from system zero
+        assert_eq!(center_of_mass_of(&bodies, 0), exact.center_of_mass);
+        assert_eq!(center_of_mass_of(&bodies, 1), None);
+        assert_eq!(center_of_mass(&[]), None);
+    }
+
+    #[test]
+    fn test_drift() {
+        let body = |id: u32, x: f64, v: f64| {
+            Body::new(
+                id,
+                Vector3::new(x, 0.0, 0.0),
+                Vector3::new(0.0, v, 0.0),
+                1.0,
+            )
+        };
+        let mut log = DiagnosticsLog::new(PotentialMethod::Exact);
+        let bodies = [body(0, 1.0, 1.0), body(1, -1.0, -1.0)];
+        log.record(&bodies, 0.0, 1.0, Softening::None).unwrap();
+        assert_eq!(log.last_drift().unwrap().energy, 0.0);
+
+        // Twice the speed of one body
+        let bodies = [body(0, 1.0, 2.0), body(1, -1.0, -1.0)];
+        log.record(&bodies, 1.0, 1.0, Softening::None).unwrap();
+        let drift = log.last_drift().unwrap();
+        // Energy goes from 1 - 0.5 to 2.5 - 0.5
+        assert!((drift.energy - 3.0).abs() < 1e-12);
+        assert!((drift.momentum - 0.5).abs() < 1e-12);
+        assert!((drift.angular_momentum - 0.5).abs() < 1e-12);
+        assert_eq!(log.drifts().len(), 2);
+
+        // Bodies at rest with no energy drift absolutely
+        let mut log = DiagnosticsLog::new(PotentialMethod::Exact);
+        log.record(&[body(0, 1.0, 0.0)], 0.0, 1.0, Softening::None)
+            .unwrap();
+        log.record(&[body(0, 1.0, 2.0)], 1.0, 1.0, Softening::None)
+            .unwrap();
+        let drift = log.last_drift().unwrap();
+        assert_eq!((drift.energy, drift.momentum), (2.0, 2.0));
+        assert_eq!(drift.angular_momentum, 2.0);
+    }
+}
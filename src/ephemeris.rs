@@ -0,0 +1,455 @@
+//! State vectors read from text exported by JPL Horizons.
+//!
+//! Every file is the vector table of one target, saved as text with the CSV
+//! option and output units of km and seconds or AU and days. The target and
+//! the center the states are relative to come from the `Target body name` and
+//! `Center body name` lines of the header, which give their names and ids as in
+//! `Mercury (199)`. The `JDTDB`, `X`, `Y`, `Z`, `VX`, `VY` and `VZ` columns
+//! are read from the rows between the `$$SOE` and `$$EOE` markers, named by the
+//! line before them. Horizons tables carry no masses, so GM is given separately.
+use std::{error::Error, fmt, fs, io, path::Path};
+
+use crate::{
+    body::Body,
+    units::{UnitSystem, AU, G},
+    vector::Vector3,
+};
+
+/// Units of Horizons tables in kilometres and seconds
+const KM_S: UnitSystem = UnitSystem {
+    length: 1000.0,
+    mass: 1.0,
+    time: 1.0,
+};
+
+/// Units of Horizons tables in astronomical units and days
+const AU_D: UnitSystem = UnitSystem {
+    length: AU,
+    mass: 1.0,
+    time: 86_400.0,
+};
+
+#[derive(Debug)]
+pub enum EphemerisError {
+    Io(io::Error),
+    /// The file has no line starting with this
+    MissingHeader(&'static str),
+    /// The line naming the columns has no column of this name
+    MissingColumn(&'static str),
+    /// A line could not be read, `line` counting from one
+    BadRow {
+        line: usize,
+        reason: String,
+    },
+    /// Target `id` has no row at Julian date `epoch`
+    MissingEpoch {
+        id: u32,
+        epoch: f64,
+    },
+    /// No GM was given for body `id`
+    MissingMass(u32),
+    /// Target `id` is relative to another center than the first target
+    MixedCenters(u32),
+}
+
+impl fmt::Display for EphemerisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EphemerisError::Io(e) => write!(f, "Could not read ephemeris: {}", e),
+            EphemerisError::MissingHeader(start) => {
+                write!(f, "Ephemeris has no line starting with {}", start)
+            }
+            EphemerisError::MissingColumn(name) => {
+                write!(f, "Ephemeris has no column {}", name)
+            }
+            EphemerisError::BadRow { line, reason } => {
+                write!(f, "Ephemeris line {}: {}", line, reason)
+            }
+            EphemerisError::MissingEpoch { id, epoch } => {
+                write!(f, "Ephemeris of body {} has no row at JD {}", id, epoch)
+            }
+            EphemerisError::MissingMass(id) => write!(f, "No GM for body {}", id),
+            EphemerisError::MixedCenters(id) => {
+                write!(f, "Ephemeris of body {} has another center", id)
+            }
+        }
+    }
+}
+
+impl Error for EphemerisError {}
+
+impl From<io::Error> for EphemerisError {
+    fn from(e: io::Error) -> Self {
+        EphemerisError::Io(e)
+    }
+}
+
+/// Vector table of one target, as exported by Horizons
+pub struct Vectors {
+    pub name: String,
+    pub id: u32,
+    /// Name and id of the body the states are relative to
+    pub center: (String, u32),
+    /// Units of the table, with mass in kg
+    pub units: UnitSystem,
+    /// Julian date (TDB) of every row
+    pub epochs: Vec<f64>,
+    /// Position and velocity of every row, in `units`
+    pub states: Vec<(Vector3, Vector3)>,
+}
+
+impl Vectors {
+    /// Read the file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Vectors, EphemerisError> {
+        Vectors::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read `text`
+    pub fn parse(text: &str) -> Result<Vectors, EphemerisError> {
+        let (mut target, mut center, mut units) = (None, None, KM_S);
+        let (mut columns, mut header): (_, Option<&str>) = (None, None);
+        let (mut epochs, mut states) = (Vec::new(), Vec::new());
+        for (i, line) in text.lines().enumerate() {
+            let bad = |reason: String| EphemerisError::BadRow {
+                line: i + 1,
+                reason,
+            };
+            let line = line.trim();
+            // Lines of asterisks separate the parts of the file
+            if line.is_empty() || line.starts_with('*') {
+                continue;
+            }
+            if let Some(columns) = columns.as_ref() {
+                if line == "$$EOE" {
+                    break;
+                }
+                let (epoch, state) = Vectors::row(columns, line).map_err(bad)?;
+                epochs.push(epoch);
+                states.push(state);
+            } else if line == "$$SOE" {
+                let names = header.ok_or(EphemerisError::MissingColumn("JDTDB"))?;
+                let names: Vec<&str> = names.split(',').map(str::trim).collect();
+                let mut indices = [0; 7];
+                for (index, name) in indices
+                    .iter_mut()
+                    .zip(["JDTDB", "X", "Y", "Z", "VX", "VY", "VZ"].iter())
+                {
+                    *index = names
+                        .iter()
+                        .position(|c| c == name)
+                        .ok_or(EphemerisError::MissingColumn(name))?;
+                }
+                columns = Some(indices);
+            } else if let Some(name) = line.strip_prefix("Target body name:") {
+                target = Some(Vectors::name(name).map_err(bad)?);
+            } else if let Some(name) = line.strip_prefix("Center body name:") {
+                center = Some(Vectors::name(name).map_err(bad)?);
+            } else if let Some(output) = line.strip_prefix("Output units") {
+                units = match output.trim_start_matches([' ', ':']) {
+                    "KM-S" => KM_S,
+                    "AU-D" => AU_D,
+                    other => return Err(bad(format!("units {} are not KM-S or AU-D", other))),
+                };
+            } else {
+                // The last line before the table names the columns
+                header = Some(line);
+            }
+        }
+        let (name, id) = target.ok_or(EphemerisError::MissingHeader("Target body name"))?;
+        let center = center.ok_or(EphemerisError::MissingHeader("Center body name"))?;
+        if columns.is_none() {
+            return Err(EphemerisError::MissingHeader("$$SOE"));
+        }
+        Ok(Vectors {
+            name,
+            id,
+            center,
+            units,
+            epochs,
+            states,
+        })
+    }
+
+    /// Name and id of `Mercury (199)   {source: DE441}`
+    fn name(text: &str) -> Result<(String, u32), String> {
+        let text = text.split('{').next().unwrap_or_default().trim();
+        let open = text.rfind('(').ok_or(format!("no body id in {}", text))?;
+        let id = text[open + 1..].trim_end_matches(')');
+        let id = id.parse().map_err(|_| format!("{} is not a body id", id))?;
+        Ok((text[..open].trim().to_string(), id))
+    }
+
+    /// Julian date and state of one row, by the indices of the JDTDB, X, Y,
+    /// Z, VX, VY and VZ columns
+    fn row(columns: &[usize; 7], line: &str) -> Result<(f64, (Vector3, Vector3)), String> {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let mut values = [0.0; 7];
+        for (value, &i) in values.iter_mut().zip(columns.iter()) {
+            let field = fields.get(i).ok_or(format!("no column {}", i + 1))?;
+            *value = field
+                .parse()
+                .map_err(|_| format!("{} is not a number", field))?;
+        }
+        let [epoch, x, y, z, vx, vy, vz] = values;
+        Ok((epoch, (Vector3::new(x, y, z), Vector3::new(vx, vy, vz))))
+    }
+
+    /// State at Julian date `epoch`, None if no row is at exactly that date
+    pub fn at(&self, epoch: f64) -> Option<(Vector3, Vector3)> {
+        self.epochs
+            .iter()
+            .position(|&e| e == epoch)
+            .map(|i| self.states[i])
+    }
+}
+
+/// Bodies of an ephemeris at one epoch with their names
+pub struct Ephemeris {
+    /// Julian date (TDB)
+    pub epoch: f64,
+    pub names: Vec<String>,
+    /// In the units the ephemeris was read in, for a simulation with the G of
+    /// those units
+    pub bodies: Vec<Body>,
+}
+
+impl Ephemeris {
+    /// Bodies of the center of `vectors` at rest at the origin, followed by
+    /// every target at Julian date `epoch`, in `units`. Masses are given by
+    /// `gm`, pairs of body id and GM in km³/s²
+    pub fn at(
+        vectors: &[Vectors],
+        epoch: f64,
+        gm: &[(u32, f64)],
+        units: &UnitSystem,
+    ) -> Result<Ephemeris, EphemerisError> {
+        let mut ephemeris = Ephemeris {
+            epoch,
+            names: Vec::new(),
+            bodies: Vec::new(),
+        };
+        // GM in km³/s², so the mass in kg is GM 10⁹ / G in SI units
+        let mass = |id: u32| {
+            gm.iter()
+                .find(|(i, _)| *i == id)
+                .map(|(_, gm)| gm * 1e9 / G)
+                .ok_or(EphemerisError::MissingMass(id))
+        };
+        let center = match vectors.first() {
+            Some(v) => &v.center,
+            None => return Ok(ephemeris),
+        };
+        let body = Body::new(center.1, Vector3::zero(), Vector3::zero(), mass(center.1)?);
+        ephemeris.names.push(center.0.clone());
+        ephemeris.bodies.push(KM_S.convert(&body, units));
+        for v in vectors.iter() {
+            if v.center.1 != center.1 {
+                return Err(EphemerisError::MixedCenters(v.id));
+            }
+            let (pos, vel) = v
+                .at(epoch)
+                .ok_or(EphemerisError::MissingEpoch { id: v.id, epoch })?;
+            let body = Body::new(v.id, pos, vel, mass(v.id)?);
+            ephemeris.names.push(v.name.clone());
+            ephemeris.bodies.push(v.units.convert(&body, units));
+        }
+        Ok(ephemeris)
+    }
+
+    /// Body called `name`
+    pub fn body(&self, name: &str) -> Option<&Body> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| &self.bodies[i])
+    }
+
+    /// Bodies called `names`, in that order, skipping names not found
+    pub fn select(&self, names: &[&str]) -> Vec<Body> {
+        names.iter().filter_map(|n| self.body(n)).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostics::momentum, integrator::Yoshida4, simulation::Simulation, softening::Softening,
+        solver::ForceSolver,
+    };
+
+    /// GM in km³/s² from the planetary mass ratios of Standish, see data/mean_elements
+    const GM: &[(u32, f64)] = &[
+        (10, 1.32712440041e11),
+        (199, 2.20320804902e4),
+        (299, 3.24858598883e5),
+        (3, 4.03503235267e5),
+        (499, 4.28283142655e4),
+        (599, 1.26712767880e8),
+        (699, 3.79406260677e7),
+    ];
+
+    fn vectors(file: &str) -> Vectors {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/data/mean_elements/");
+        Vectors::load(format!("{}{}.txt", dir, file)).unwrap()
+    }
+
+    fn solar_system() -> Ephemeris {
+        let vectors: Vec<Vectors> = [
+            "mercury",
+            "venus",
+            "earth_moon_barycenter",
+            "mars",
+            "jupiter",
+            "saturn",
+        ]
+        .iter()
+        .map(|file| vectors(file))
+        .collect();
+        Ephemeris::at(&vectors, 2451545.0, GM, &UnitSystem::ASTRONOMICAL).unwrap()
+    }
+
+    #[test]
+    fn test_load() {
+        let mercury = vectors("mercury");
+        assert_eq!((mercury.name.as_str(), mercury.id), ("Mercury", 199));
+        assert_eq!(mercury.center, ("Sun".to_string(), 10));
+        assert_eq!(mercury.epochs, vec![2451545.0, 2451546.0, 2451547.0]);
+        assert_eq!(mercury.states[0].1.x, 3.699478326895671e1);
+        assert!(mercury.at(2451546.0).is_some() && mercury.at(2451546.5).is_none());
+
+        let ephemeris = solar_system();
+        assert_eq!(ephemeris.epoch, 2451545.0);
+        assert_eq!(ephemeris.bodies.len(), 7);
+        let earth = ephemeris.body("Earth-Moon Barycenter").unwrap();
+        assert_eq!(earth.id, 3);
+        // About 1 AU from the Sun at 2π AU per year
+        assert!((earth.pos.dot(earth.pos).sqrt() - 0.983).abs() < 1e-2);
+        assert!((earth.vel.dot(earth.vel).sqrt() / std::f64::consts::TAU - 1.0).abs() < 0.03);
+        let sun = ephemeris.body("Sun").unwrap();
+        assert!((sun.mass - 1.0).abs() < 1e-3);
+        assert_eq!(sun.pos, Vector3::zero());
+        assert_eq!(ephemeris.select(&["Mars", "Pluto", "Sun"]).len(), 2);
+
+        // The same state in AU and days
+        let text = "Target body name: Mercury (199)  {source: DE441}\n\
+                    Center body name: Sun (10)\n\
+                    Output units    : AU-D\n\
+                    JDTDB, Calendar Date (TDB), X, Y, Z, VX, VY, VZ,\n\
+                    $$SOE\n\
+                    2451545.0, A.D. 2000-Jan-01 12:00:00.0000, 1, 0, 0, 0, 0.01, 0,\n\
+                    $$EOE";
+        let au_d = Vectors::parse(text).unwrap();
+        let ephemeris = Ephemeris::at(&[au_d], 2451545.0, GM, &UnitSystem::SI).unwrap();
+        let b = ephemeris.bodies[1];
+        assert!((b.pos.x / AU - 1.0).abs() < 1e-12);
+        assert!((b.vel.y * 86_400.0 / AU / 0.01 - 1.0).abs() < 1e-12);
+
+        match Vectors::parse(&text.replace("Target", "Observer")) {
+            Err(EphemerisError::MissingHeader("Target body name")) => (),
+            _ => panic!("expected a missing target"),
+        }
+        match Vectors::parse(&text.replace(" VX,", " V,")) {
+            Err(EphemerisError::MissingColumn("VX")) => (),
+            _ => panic!("expected a missing column"),
+        }
+        match Vectors::parse(&text.replace(", 0.01,", ", zero,")) {
+            Err(EphemerisError::BadRow { line: 6, .. }) => (),
+            _ => panic!("expected a bad row"),
+        }
+        let vectors = [Vectors::parse(text).unwrap()];
+        match Ephemeris::at(&vectors, 2451546.0, GM, &UnitSystem::SI) {
+            Err(EphemerisError::MissingEpoch { id: 199, .. }) => (),
+            _ => panic!("expected a missing epoch"),
+        }
+        match Ephemeris::at(&vectors, 2451545.0, &GM[..1], &UnitSystem::SI) {
+            Err(EphemerisError::MissingMass(199)) => (),
+            _ => panic!("expected a missing mass"),
+        }
+    }
+
+    /// Run of a century, giving the longitude of perihelion of Mercury about
+    /// the Sun in arcseconds every `sample` steps, by time in centuries, and the
+    /// times in years at which the Earth passes the x axis about the Sun
+    fn century(bodies: Vec<Body>, dt: f64, sample: usize) -> (Vec<(f64, f64)>, Vec<f64>) {
+        let mut sim = Simulation::new(bodies, dt, 0.0);
+        sim.g = UnitSystem::ASTRONOMICAL.g();
+        sim.softening = Softening::None;
+        sim.solver = ForceSolver::Direct;
+        sim.integrator = Box::new(Yoshida4);
+        let (mut longitudes, mut passes) = (Vec::new(), Vec::new());
+        let (mut last, mut last_angle) = (0.0, f64::NAN);
+        let steps = (100.0 / dt).round() as usize;
+        for i in 0..=steps {
+            if i % sample == 0 {
+                let e = sim.elements(199, 10).unwrap();
+                let mut longitude = e.ascending_node + e.argument_of_periapsis;
+                // Unwrap across whole turns
+                longitude +=
+                    std::f64::consts::TAU * ((last - longitude) / std::f64::consts::TAU).round();
+                last = longitude;
+                longitudes.push((sim.time / 100.0, longitude.to_degrees() * 3600.0));
+            }
+            let find = |id: u32| sim.bodies.iter().find(|b| b.id == id).map(|b| b.pos);
+            if let (Some(earth), Some(sun)) = (find(3), find(10)) {
+                let angle = (earth.y - sun.y).atan2(earth.x - sun.x);
+                // Interpolated between the steps before and after
+                if last_angle < 0.0 && angle >= 0.0 && angle - last_angle < 1.0 {
+                    passes.push(sim.time - dt * angle / (angle - last_angle));
+                }
+                last_angle = angle;
+            }
+            if i < steps {
+                sim.update().unwrap();
+            }
+        }
+        (longitudes, passes)
+    }
+
+    /// Least squares slope of `points`
+    fn slope(points: &[(f64, f64)]) -> f64 {
+        let n = points.len() as f64;
+        let (mx, my) = points
+            .iter()
+            .fold((0.0, 0.0), |(x, y), p| (x + p.0 / n, y + p.1 / n));
+        let (sxy, sxx) = points.iter().fold((0.0, 0.0), |(sxy, sxx), p| {
+            (sxy + (p.0 - mx) * (p.1 - my), sxx + (p.0 - mx) * (p.0 - mx))
+        });
+        sxy / sxx
+    }
+
+    /// A century of the inner planets started from the mean element states,
+    /// which checks the integrator and the loaded units, not an ephemeris
+    #[test]
+    fn test_mean_element_century() {
+        let ephemeris = solar_system();
+        let mut inner =
+            ephemeris.select(&["Sun", "Mercury", "Venus", "Earth-Moon Barycenter", "Mars"]);
+        // Barycentric frame, so the system does not wander off
+        let mass: f64 = inner.iter().map(|b| b.mass).sum();
+        let drift = momentum(&inner) / mass;
+        for b in inner.iter_mut() {
+            b.vel = b.vel - drift;
+        }
+
+        let dt = 0.5 / 365.25;
+        let (perturbed, passes) = century(inner, dt, 20);
+
+        // A hundred sidereal years of 365.256 days
+        assert_eq!(passes.len(), 100);
+        let year = (passes[99] - passes[0]) / 99.0 * 365.25;
+        assert!((year - 365.256).abs() < 0.01, "year of {} days", year);
+
+        // Newtonian precession from Venus, the Earth and Mars of about 370″
+        // per century, against about 530″ with the outer planets. The
+        // integrator turns the orbit by itself too, which a run of Mercury
+        // alone about the Sun measures
+        let (alone, _) = century(ephemeris.select(&["Sun", "Mercury"]), dt, 20);
+        let precession = slope(&perturbed) - slope(&alone);
+        assert!(
+            (precession / 370.0 - 1.0).abs() < 0.1,
+            "{} arcseconds per century",
+            precession
+        );
+    }
+}
@@ -0,0 +1,24 @@
+//! Bodies shared by the tests
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{body::Body, vector::Vector3};
+
+/// `n` bodies at rest, spread uniformly over the cube of half size `half`
+/// around the origin, with masses from 1 to 100, drawn from `seed`
+pub fn random_bodies(n: u32, half: f64, seed: u64) -> Vec<Body> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n)
+        .map(|i| {
+            Body::new(
+                i,
+                Vector3::new(
+                    rng.gen_range(-half..half),
+                    rng.gen_range(-half..half),
+                    rng.gen_range(-half..half),
+                ),
+                Vector3::zero(),
+                rng.gen_range(1.0..100.0),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,527 @@
+//! Fast multipole method with Cartesian Taylor expansions.
+//!
+//! Expansions are attached to the nodes of an `OcTree`, copied into a flat
+//! list ordered by depth with body indices in the leaves. A dual tree walk
+//! lists the well separated node pairs, whose multipoles are translated into
+//! local expansions, and the neighbouring leaves, which are summed directly.
+//! Every pass then runs over the nodes of one depth, or over the targets of
+//! the interactions, in parallel.
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::{
+    body::Body,
+    cube::Cube,
+    octree::{OcTree, OcTreeError},
+    physics_helper::calc_pull,
+    softening::Softening,
+    vector::Vector3,
+};
+
+/// Bucket size of the tree, leaves are summed directly with their neighbours
+const LEAF_SIZE: usize = 16;
+
+/// Multi-indices (a, b, c) of Cartesian expansion terms up to some order
+struct Terms {
+    /// Highest total order a + b + c
+    order: usize,
+    indices: Vec<[usize; 3]>,
+    /// Position in `indices` of every (a, b, c), `usize::MAX` above `order`
+    lookup: Vec<usize>,
+    factorial: Vec<f64>,
+}
+
+impl Terms {
+    fn new(order: usize) -> Self {
+        let side = order + 1;
+        let mut indices = Vec::new();
+        let mut lookup = vec![usize::MAX; side * side * side];
+        for n in 0..=order {
+            for a in (0..=n).rev() {
+                for b in (0..=(n - a)).rev() {
+                    let c = n - a - b;
+                    lookup[(a * side + b) * side + c] = indices.len();
+                    indices.push([a, b, c]);
+                }
+            }
+        }
+        let mut factorial = vec![1.0; side];
+        for i in 1..side {
+            factorial[i] = factorial[i - 1] * i as f64;
+        }
+        Terms {
+            order,
+            indices,
+            lookup,
+            factorial,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn index(&self, k: [usize; 3]) -> usize {
+        let side = self.order + 1;
+        self.lookup[(k[0] * side + k[1]) * side + k[2]]
+    }
+
+    /// k! of a multi-index
+    fn factorial(&self, k: [usize; 3]) -> f64 {
+        self.factorial[k[0]] * self.factorial[k[1]] * self.factorial[k[2]]
+    }
+
+    /// d^k for every term k
+    fn powers(&self, d: Vector3) -> Vec<f64> {
+        let mut px = vec![1.0; self.order + 1];
+        let mut py = vec![1.0; self.order + 1];
+        let mut pz = vec![1.0; self.order + 1];
+        for i in 1..=self.order {
+            px[i] = px[i - 1] * d.x;
+            py[i] = py[i - 1] * d.y;
+            pz[i] = pz[i - 1] * d.z;
+        }
+        self.indices
+            .iter()
+            .map(|k| px[k[0]] * py[k[1]] * pz[k[2]])
+            .collect()
+    }
+
+    /// Derivatives of 1/sqrt(|r|² + eps²) for every term, by the
+    /// McMurchie-Davidson recurrence, which holds for any function of |r|²
+    fn derivatives(&self, r: Vector3, eps: f64) -> Vec<f64> {
+        let n = self.len();
+        let r2 = r.dot(r) + eps * eps;
+        let inv_r = 1.0 / r2.sqrt();
+        let inv_r2 = inv_r * inv_r;
+
+        // aux[m * n + i] is the i-th derivative of (-1)^m (2m-1)!! / r^(2m+1),
+        // r including eps
+        let mut aux = vec![0.0; (self.order + 1) * n];
+        let mut base = inv_r;
+        for m in 0..=self.order {
+            aux[m * n] = base;
+            base *= -((2 * m + 1) as f64) * inv_r2;
+        }
+        for (i, k) in self.indices.iter().enumerate().skip(1) {
+            let total = k[0] + k[1] + k[2];
+            let (axis, x) = if k[0] > 0 {
+                (0, r.x)
+            } else if k[1] > 0 {
+                (1, r.y)
+            } else {
+                (2, r.z)
+            };
+            let mut lower = *k;
+            lower[axis] -= 1;
+            let lower1 = self.index(lower);
+            let lower2 = if lower[axis] > 0 {
+                let mut l = lower;
+                l[axis] -= 1;
+                Some(self.index(l))
+            } else {
+                None
+            };
+            for m in 0..=(self.order - total) {
+                let mut v = x * aux[(m + 1) * n + lower1];
+                if let Some(l2) = lower2 {
+                    v += lower[axis] as f64 * aux[(m + 1) * n + l2];
+                }
+                aux[m * n + i] = v;
+            }
+        }
+        aux.truncate(n);
+        aux
+    }
+}
+
+struct Node {
+    center: Vector3,
+    /// Radius of the sphere around `center` holding the whole cube
+    radius: f64,
+    size: f64,
+    /// Largest softening length of its bodies, None when none has its own
+    softening: Option<f64>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Indices into the body slice, only set for leaves
+    bodies: Vec<usize>,
+}
+
+struct Fmm<'a> {
+    bodies: &'a [Body],
+    theta: f64,
+    softening: Softening,
+    /// Local expansion and translation terms, one order above the multipoles
+    terms: Terms,
+    /// Number of leading `terms` in a multipole expansion
+    multipole_terms: usize,
+    nodes: Vec<Node>,
+    /// Nodes at every depth, from the top
+    levels: Vec<Range<usize>>,
+}
+
+impl<'a> Fmm<'a> {
+    /// Copy the nodes of `ot`, whose bodies have their index as id, breadth first
+    fn flatten(&mut self, ot: &OcTree) {
+        let mut queue = vec![(ot, None)];
+        while !queue.is_empty() {
+            let start = self.nodes.len();
+            let mut next = Vec::new();
+            for (ot, parent) in queue {
+                let id = self.nodes.len();
+                let boundary = ot.boundary();
+                let mut node = Node {
+                    center: boundary.center(),
+                    radius: boundary.size * 3f64.sqrt() / 2.0,
+                    size: boundary.size,
+                    softening: ot.softening(),
+                    parent,
+                    children: Vec::new(),
+                    bodies: Vec::new(),
+                };
+                match ot {
+                    OcTree::Leaf(leaf) => {
+                        node.bodies = leaf.bodies.iter().map(|b| b.id as usize).collect();
+                    }
+                    OcTree::Root(root) => {
+                        next.extend(root.children().map(|child| (child, Some(id))));
+                    }
+                }
+                if let Some(parent) = parent {
+                    self.nodes[parent].children.push(id);
+                }
+                self.nodes.push(node);
+            }
+            self.levels.push(start..self.nodes.len());
+            queue = next;
+        }
+    }
+
+    fn is_leaf(&self, id: usize) -> bool {
+        self.nodes[id].children.is_empty()
+    }
+
+    /// Softening length the kernel between two nodes is expanded with
+    fn kernel_length(&self, a: usize, b: usize) -> f64 {
+        match self
+            .softening
+            .pair(self.nodes[a].softening, self.nodes[b].softening)
+        {
+            Softening::Plummer(eps) => eps,
+            _ => 0.0,
+        }
+    }
+
+    fn well_separated(&self, a: usize, b: usize) -> bool {
+        let (na, nb) = (&self.nodes[a], &self.nodes[b]);
+        let d = na.center - nb.center;
+        let (d, radii) = (d.dot(d).sqrt(), na.radius + nb.radius);
+        if radii >= self.theta * d {
+            return false;
+        }
+        // The spline kernel only has an expansion where it is Newtonian
+        let kernel = self.softening.pair(na.softening, nb.softening);
+        match kernel {
+            Softening::Spline(h) => d - radii >= h,
+            _ => true,
+        }
+    }
+
+    /// Sources of multipole to local and of direct interactions of every
+    /// node, from a dual tree walk of sources in `b` on targets in `a`
+    fn interactions(&self, a: usize, b: usize, m2l: &mut [Vec<usize>], p2p: &mut [Vec<usize>]) {
+        if a != b && self.well_separated(a, b) {
+            m2l[a].push(b);
+        } else if self.is_leaf(a) && self.is_leaf(b) {
+            p2p[a].push(b);
+        } else if self.is_leaf(b) || (!self.is_leaf(a) && self.nodes[a].size >= self.nodes[b].size)
+        {
+            for &c in self.nodes[a].children.iter() {
+                self.interactions(c, b, m2l, p2p);
+            }
+        } else {
+            for &c in self.nodes[b].children.iter() {
+                self.interactions(a, c, m2l, p2p);
+            }
+        }
+    }
+
+    /// Particle to multipole and multipole to multipole of the children
+    fn multipole(&self, id: usize, multipoles: &[Vec<f64>]) -> Vec<f64> {
+        let center = self.nodes[id].center;
+        let mut multipole = vec![0.0; self.multipole_terms];
+        for &i in self.nodes[id].bodies.iter() {
+            let b = &self.bodies[i];
+            let powers = self.terms.powers(b.pos - center);
+            for (k, m) in multipole.iter_mut().enumerate() {
+                *m += b.mass * powers[k] / self.terms.factorial(self.terms.indices[k]);
+            }
+        }
+        for &c in self.nodes[id].children.iter() {
+            let powers = self.terms.powers(self.nodes[c].center - center);
+            let indices = &self.terms.indices[..self.multipole_terms];
+            for (k, kk) in indices.iter().enumerate() {
+                for (j, jj) in indices.iter().enumerate() {
+                    if jj[0] <= kk[0] && jj[1] <= kk[1] && jj[2] <= kk[2] {
+                        let diff = [kk[0] - jj[0], kk[1] - jj[1], kk[2] - jj[2]];
+                        multipole[k] += multipoles[c][j] * powers[self.terms.index(diff)]
+                            / self.terms.factorial(diff);
+                    }
+                }
+            }
+        }
+        multipole
+    }
+
+    /// Multipoles of `sources` to a local expansion of `a`
+    fn m2l(&self, a: usize, sources: &[usize], multipoles: &[Vec<f64>]) -> Vec<f64> {
+        let order = self.terms.order;
+        let mut local = vec![0.0; self.terms.len()];
+        for &b in sources.iter() {
+            let d = self.terms.derivatives(
+                self.nodes[a].center - self.nodes[b].center,
+                self.kernel_length(a, b),
+            );
+            let multipole = &multipoles[b];
+            for (n, nn) in self.terms.indices.iter().enumerate() {
+                let n_order = nn[0] + nn[1] + nn[2];
+                let mut sum = 0.0;
+                for (k, kk) in self.terms.indices[..self.multipole_terms]
+                    .iter()
+                    .enumerate()
+                {
+                    let k_order = kk[0] + kk[1] + kk[2];
+                    if n_order + k_order > order {
+                        break;
+                    }
+                    let sign = if k_order % 2 == 0 { 1.0 } else { -1.0 };
+                    let nk = [nn[0] + kk[0], nn[1] + kk[1], nn[2] + kk[2]];
+                    sum += sign * multipole[k] * d[self.terms.index(nk)];
+                }
+                local[n] += sum / self.terms.factorial(*nn);
+            }
+        }
+        local
+    }
+
+    /// Local expansion of the parent of `id` shifted to `id`
+    fn l2l(&self, id: usize, parent: usize, locals: &[Vec<f64>]) -> Vec<f64> {
+        let powers = self
+            .terms
+            .powers(self.nodes[id].center - self.nodes[parent].center);
+        let mut local = locals[id].clone();
+        for (j, jj) in self.terms.indices.iter().enumerate() {
+            for (n, nn) in self.terms.indices.iter().enumerate() {
+                if jj[0] <= nn[0] && jj[1] <= nn[1] && jj[2] <= nn[2] {
+                    let diff = [nn[0] - jj[0], nn[1] - jj[1], nn[2] - jj[2]];
+                    let binomial = self.terms.factorial(*nn)
+                        / (self.terms.factorial(*jj) * self.terms.factorial(diff));
+                    local[j] += locals[parent][n] * binomial * powers[self.terms.index(diff)];
+                }
+            }
+        }
+        local
+    }
+
+    /// Local to particle and direct sums from the leaves in `sources`, for
+    /// the bodies of leaf `a`
+    fn leaf_forces(&self, a: usize, sources: &[usize], local: &[f64]) -> Vec<(usize, Vector3)> {
+        let center = self.nodes[a].center;
+        self.nodes[a]
+            .bodies
+            .iter()
+            .map(|&i| {
+                let b1 = &self.bodies[i];
+                let powers = self.terms.powers(b1.pos - center);
+                let mut acc = Vector3::zero();
+                for (n, nn) in self.terms.indices.iter().enumerate() {
+                    let l = local[n];
+                    if nn[0] > 0 {
+                        let p = powers[self.terms.index([nn[0] - 1, nn[1], nn[2]])];
+                        acc.x += l * nn[0] as f64 * p;
+                    }
+                    if nn[1] > 0 {
+                        let p = powers[self.terms.index([nn[0], nn[1] - 1, nn[2]])];
+                        acc.y += l * nn[1] as f64 * p;
+                    }
+                    if nn[2] > 0 {
+                        let p = powers[self.terms.index([nn[0], nn[1], nn[2] - 1])];
+                        acc.z += l * nn[2] as f64 * p;
+                    }
+                }
+                let mut force = acc * b1.mass;
+                for &b in sources.iter() {
+                    for &j in self.nodes[b].bodies.iter() {
+                        if i != j {
+                            force = force + calc_pull(b1, &self.bodies[j], self.softening);
+                        }
+                    }
+                }
+                (i, force)
+            })
+            .collect()
+    }
+
+    fn forces(&self) -> Vec<Vector3> {
+        let n = self.nodes.len();
+
+        // Upward, deepest nodes first
+        let mut multipoles = vec![Vec::new(); n];
+        for level in self.levels.iter().rev() {
+            let level_multipoles: Vec<Vec<f64>> = level
+                .clone()
+                .into_par_iter()
+                .map(|id| self.multipole(id, &multipoles))
+                .collect();
+            for (id, multipole) in level.clone().zip(level_multipoles) {
+                multipoles[id] = multipole;
+            }
+        }
+
+        let (mut m2l, mut p2p) = (vec![Vec::new(); n], vec![Vec::new(); n]);
+        self.interactions(0, 0, &mut m2l, &mut p2p);
+        let mut locals: Vec<Vec<f64>> = (0..n)
+            .into_par_iter()
+            .map(|a| self.m2l(a, &m2l[a], &multipoles))
+            .collect();
+
+        // Downward, top nodes first
+        for level in self.levels.iter().skip(1) {
+            let level_locals: Vec<Vec<f64>> = level
+                .clone()
+                .into_par_iter()
+                .map(|id| self.l2l(id, self.nodes[id].parent.unwrap(), &locals))
+                .collect();
+            for (id, local) in level.clone().zip(level_locals) {
+                locals[id] = local;
+            }
+        }
+
+        let leaf_forces: Vec<Vec<(usize, Vector3)>> = (0..n)
+            .into_par_iter()
+            .filter(|&a| self.is_leaf(a))
+            .map(|a| self.leaf_forces(a, &p2p[a], &locals[a]))
+            .collect();
+        let mut forces = vec![Vector3::zero(); self.bodies.len()];
+        for (i, force) in leaf_forces.into_iter().flatten() {
+            forces[i] = force;
+        }
+        forces
+    }
+}
+
+/// Force on every body by the fast multipole method, not G.
+///
+/// Node pairs are well separated when the sum of their radii is less than
+/// `theta` times the distance between them. Multipoles are truncated at
+/// `order`, where order zero is a monopole, and local expansions one above.
+/// Nodes are softened with the largest length of their bodies, and with the
+/// spline kernel only expanded once they are further apart than its support.
+/// Parallel on the current rayon pool. Every body is expected to be inside
+/// `boundary`.
+pub fn fmm_forces(
+    bodies: &[Body],
+    boundary: Cube,
+    order: usize,
+    theta: f64,
+    softening: Softening,
+) -> Result<Vec<Vector3>, OcTreeError> {
+    if bodies.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Ids are indices into `bodies` in the tree
+    let indexed: Vec<Body> = bodies
+        .iter()
+        .enumerate()
+        .map(|(i, b)| Body { id: i as u32, ..*b })
+        .collect();
+    let ot = OcTree::build_parallel(boundary, LEAF_SIZE, &indexed)?;
+    let mut fmm = Fmm {
+        bodies,
+        theta,
+        softening,
+        terms: Terms::new(order + 1),
+        multipole_terms: Terms::new(order).len(),
+        nodes: Vec::new(),
+        levels: Vec::new(),
+    };
+    fmm.flatten(&ot);
+    Ok(fmm.forces())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, solver::direct_forces};
+    use rayon::ThreadPoolBuilder;
+
+    const BOUNDARY: Cube = Cube {
+        pos: Vector3 {
+            x: -10.0,
+            y: -10.0,
+            z: -10.0,
+        },
+        size: 20.0,
+    };
+
+    /// Relative errors against direct summation, as RMS and largest
+    fn errors(bodies: &[Body], order: usize, theta: f64, softening: Softening) -> (f64, f64) {
+        let exact = direct_forces(softening, bodies);
+        let approx = fmm_forces(bodies, BOUNDARY, order, theta, softening).unwrap();
+        let errors: Vec<f64> = exact
+            .iter()
+            .zip(approx.iter())
+            .map(|(f, a)| {
+                let diff = *a - *f;
+                diff.dot(diff) / f.dot(*f)
+            })
+            .collect();
+        let rms = (errors.iter().sum::<f64>() / errors.len() as f64).sqrt();
+        (rms, errors.iter().fold(0.0f64, |m, e| m.max(e.sqrt())))
+    }
+
+    #[test]
+    fn test_fmm_converges_with_order() {
+        let bodies = fixtures::random_bodies(1000, 10.0, 3);
+        let errors: Vec<f64> = (1..=4)
+            .map(|order| errors(&bodies, order, 0.7, Softening::None).0)
+            .collect();
+        for pair in errors.windows(2) {
+            assert!(pair[1] < pair[0], "errors {:?}", errors);
+        }
+        assert!(errors[3] < 1e-3, "errors {:?}", errors);
+    }
+
+    #[test]
+    fn test_fmm_matches_direct() {
+        let mut bodies = fixtures::random_bodies(1000, 10.0, 3);
+        for &softening in [
+            Softening::None,
+            Softening::Plummer(0.5),
+            Softening::Spline(1.5),
+        ]
+        .iter()
+        {
+            let (rms, max) = errors(&bodies, 4, 0.6, softening);
+            assert!(rms < 5e-4 && max < 5e-3, "{:?}: {} {}", softening, rms, max);
+        }
+        // Nodes are softened with the lengths of their bodies
+        for b in bodies.iter_mut() {
+            b.softening = Some(2.0);
+        }
+        let (rms, max) = errors(&bodies, 4, 0.6, Softening::Plummer(0.5));
+        assert!(rms < 5e-4 && max < 5e-3, "{} {}", rms, max);
+
+        // The same on any number of threads
+        let forces = |threads: usize| {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| fmm_forces(&bodies, BOUNDARY, 3, 0.5, Softening::None).unwrap())
+        };
+        assert_eq!(forces(1), forces(4));
+    }
+}
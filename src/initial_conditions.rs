@@ -0,0 +1,496 @@
+//! Equilibrium spheres sampled from their distribution functions.
+//!
+//! Every model returns `n` bodies of equal mass with ids from zero, moved so
+//! their center of mass is at rest at the origin. Velocities are drawn at every
+//! radius from p(v) ∝ v² f(ψ - v²/2), with ψ the relative potential and f the
+//! isotropic distribution function of the model. Models of infinite extent
+//! are cut at `TRUNCATION` scale radii, keeping velocities of the full model.
+//!
+//! `DiskGalaxy` puts a rotating exponential disk in a bulge and a halo sampled
+//! this way, each of the spheres in equilibrium on its own.
+use rand::Rng;
+
+use crate::{body::Body, vector::Vector3};
+
+/// Outer radius of Plummer and Hernquist spheres in scale radii, holding all but
+/// 1.5e-4 and 2% of their mass. Bodies farther out would throw off the center of mass
+pub const TRUNCATION: f64 = 100.0;
+
+/// Plummer sphere of total `mass` and scale radius `scale_radius`, for
+/// gravitational constant `g`. Its half-mass radius is about 1.305 scale radii
+pub fn plummer(n: u32, mass: f64, scale_radius: f64, g: f64, rng: &mut impl Rng) -> Vec<Body> {
+    let a = scale_radius;
+    sphere(n, mass, rng, |rng| {
+        // Inverse of the cumulative mass r³ / (r² + a²)^(3/2)
+        let cut = (1.0 + 1.0 / (TRUNCATION * TRUNCATION)).powf(-1.5);
+        let x: f64 = rng.gen_range(f64::EPSILON..cut);
+        let r = a / (x.powf(-2.0 / 3.0) - 1.0).sqrt();
+        let psi = g * mass / (r * r + a * a).sqrt();
+        let v = sample_speed(psi, |e| e.powf(3.5), rng);
+        (r, v)
+    })
+}
+
+/// Hernquist sphere of total `mass` and scale radius `scale_radius`, for
+/// gravitational constant `g`. Its half-mass radius is 1 + √2 scale radii
+pub fn hernquist(n: u32, mass: f64, scale_radius: f64, g: f64, rng: &mut impl Rng) -> Vec<Body> {
+    let a = scale_radius;
+    let psi0 = g * mass / a;
+    // Distribution function of Hernquist (1990) up to a constant, in q² = ε / ψ(0)
+    let df = |e: f64| {
+        let q2 = (e / psi0).min(1.0 - 1e-12);
+        let q = q2.sqrt();
+        let s = (1.0 - q2).sqrt();
+        (3.0 * q.asin() + q * s * (1.0 - 2.0 * q2) * (8.0 * q2 * q2 - 8.0 * q2 - 3.0))
+            / (s * s * s * s * s)
+    };
+    sphere(n, mass, rng, |rng| {
+        // Inverse of the cumulative mass r² / (r + a)²
+        let cut = (TRUNCATION / (TRUNCATION + 1.0)).powi(2);
+        let x: f64 = rng.gen_range(0.0..cut);
+        let r = a * x.sqrt() / (1.0 - x.sqrt());
+        let v = sample_speed(psi0 * a / (r + a), df, rng);
+        (r, v)
+    })
+}
+
+/// King model of total `mass`, King radius `scale_radius` and dimensionless
+/// central potential `w0`, for gravitational constant `g`. Larger `w0` gives
+/// a more concentrated model with a larger tidal radius, typically 3 to 12
+pub fn king(
+    n: u32,
+    mass: f64,
+    scale_radius: f64,
+    w0: f64,
+    g: f64,
+    rng: &mut impl Rng,
+) -> Vec<Body> {
+    let profile = KingProfile::new(w0);
+    // G M(r) = -r0 σ² r̃² dW/dr̃ at the tidal radius gives the velocity dispersion
+    let sigma = (g * mass / (scale_radius * profile.total_mass())).sqrt();
+    sphere(n, mass, rng, |rng| {
+        let (r, w) = profile.sample(rng.gen_range(0.0..1.0));
+        let u = sample_speed(w, |e| e.exp() - 1.0, rng);
+        (r * scale_radius, u * sigma)
+    })
+}
+
+/// Tidal radius of the King model of central potential `w0`, in King radii
+pub fn king_tidal_radius(w0: f64) -> f64 {
+    KingProfile::new(w0).tidal_radius()
+}
+
+/// Radius and potential of a King model in units of the King radius and σ²,
+/// integrated outwards from the center up to the tidal radius
+struct KingProfile {
+    radius: Vec<f64>,
+    potential: Vec<f64>,
+    /// Mass inside every radius, in units of r0 σ² / G
+    mass: Vec<f64>,
+}
+
+impl KingProfile {
+    fn new(w0: f64) -> Self {
+        // Density relative to the center, with ∇²W = -9 ρ / ρ0
+        let density = |w: f64| {
+            if w <= 0.0 {
+                return 0.0;
+            }
+            w.exp() * erf(w.sqrt())
+                - (4.0 * w / std::f64::consts::PI).sqrt() * (1.0 + 2.0 * w / 3.0)
+        };
+        let rho0 = density(w0);
+        let derivative = |r: f64, w: f64, dw: f64| (dw, -9.0 * density(w) / rho0 - 2.0 * dw / r);
+
+        // Start just off the center where W = W0 - 3/2 r²
+        let mut r = 1e-4;
+        let (mut w, mut dw) = (w0 - 1.5 * r * r, -3.0 * r);
+        let mut profile = KingProfile {
+            radius: vec![0.0, r],
+            potential: vec![w0, w],
+            mass: vec![0.0, -r * r * dw],
+        };
+        while w > 0.0 {
+            let h = 1e-3 * r.max(1.0);
+            let (k1w, k1d) = derivative(r, w, dw);
+            let (k2w, k2d) = derivative(r + h / 2.0, w + k1w * h / 2.0, dw + k1d * h / 2.0);
+            let (k3w, k3d) = derivative(r + h / 2.0, w + k2w * h / 2.0, dw + k2d * h / 2.0);
+            let (k4w, k4d) = derivative(r + h, w + k3w * h, dw + k3d * h);
+            w += (k1w + 2.0 * k2w + 2.0 * k3w + k4w) * h / 6.0;
+            dw += (k1d + 2.0 * k2d + 2.0 * k3d + k4d) * h / 6.0;
+            r += h;
+            profile.radius.push(r);
+            profile.potential.push(w.max(0.0));
+            profile.mass.push(-r * r * dw);
+        }
+        profile
+    }
+
+    fn total_mass(&self) -> f64 {
+        self.mass[self.mass.len() - 1]
+    }
+
+    fn tidal_radius(&self) -> f64 {
+        self.radius[self.radius.len() - 1]
+    }
+
+    /// Radius and potential inside which `fraction` of the mass lies
+    fn sample(&self, fraction: f64) -> (f64, f64) {
+        let m = fraction * self.total_mass();
+        let i = self
+            .mass
+            .partition_point(|&x| x < m)
+            .clamp(1, self.mass.len() - 1);
+        let t = (m - self.mass[i - 1]) / (self.mass[i] - self.mass[i - 1]);
+        let lerp = |v: &[f64]| v[i - 1] + (v[i] - v[i - 1]) * t;
+        (lerp(&self.radius), lerp(&self.potential))
+    }
+}
+
+/// Disk radius in scale lengths beyond which no disk bodies are placed,
+/// holding all but 5e-4 of the disk mass
+pub const DISK_TRUNCATION: f64 = 10.0;
+
+/// Disk galaxy of an exponential disk, a Hernquist bulge, a Hernquist dark
+/// matter halo and an optional central black hole, made by `build`.
+///
+/// Disk bodies circle at the speed set by the mass enclosed by a sphere of
+/// their radius, slowed by asymmetric drift, with a radial dispersion set by
+/// `toomre_q` and the vertical dispersion of an isothermal sheet.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct DiskGalaxy {
+    pub disk_mass: f64,
+    /// Scale length of the surface density Σ ∝ exp(-R / R_d)
+    pub disk_scale_length: f64,
+    /// Scale height of the vertical density ρ ∝ sech²(z / z_0)
+    pub disk_scale_height: f64,
+    pub disk_bodies: u32,
+    pub bulge_mass: f64,
+    pub bulge_scale_radius: f64,
+    pub bulge_bodies: u32,
+    pub halo_mass: f64,
+    pub halo_scale_radius: f64,
+    pub halo_bodies: u32,
+    /// Toomre stability parameter of the disk, above 1 for a stable disk
+    pub toomre_q: f64,
+    /// Mass of a black hole at the center
+    pub black_hole: Option<f64>,
+    /// Gravitational constant
+    pub g: f64,
+}
+
+impl DiskGalaxy {
+    /// Galaxy of `n` bodies with a disk of `disk_mass` and scale length
+    /// `disk_scale_length`, for gravitational constant `g`. Half of the bodies
+    /// are in the disk, a tenth in a bulge of a fifth of the disk mass and the
+    /// rest in a halo of five times the disk mass
+    pub fn new(disk_mass: f64, disk_scale_length: f64, n: u32, g: f64) -> Self {
+        let disk_bodies = n / 2;
+        let bulge_bodies = n / 10;
+        DiskGalaxy {
+            disk_mass,
+            disk_scale_length,
+            disk_scale_height: 0.1 * disk_scale_length,
+            disk_bodies,
+            bulge_mass: 0.2 * disk_mass,
+            bulge_scale_radius: 0.2 * disk_scale_length,
+            bulge_bodies,
+            halo_mass: 5.0 * disk_mass,
+            halo_scale_radius: 4.0 * disk_scale_length,
+            halo_bodies: n - disk_bodies - bulge_bodies,
+            toomre_q: 1.5,
+            black_hole: None,
+            g,
+        }
+    }
+
+    /// Bodies of the disk, bulge, halo and black hole in that order, with ids
+    /// from zero and their center of mass at rest at the origin. The disk
+    /// rotates about the z axis
+    pub fn build(&self, rng: &mut impl Rng) -> Vec<Body> {
+        let mut bodies = self.disk(rng);
+        bodies.extend(hernquist(
+            self.bulge_bodies,
+            self.bulge_mass,
+            self.bulge_scale_radius,
+            self.g,
+            rng,
+        ));
+        bodies.extend(hernquist(
+            self.halo_bodies,
+            self.halo_mass,
+            self.halo_scale_radius,
+            self.g,
+            rng,
+        ));
+        if let Some(mass) = self.black_hole {
+            bodies.push(Body::new(0, Vector3::zero(), Vector3::zero(), mass));
+        }
+        for (id, b) in bodies.iter_mut().enumerate() {
+            b.id = id as u32;
+        }
+        to_rest(&mut bodies);
+        bodies
+    }
+
+    /// Mass of all components inside a sphere of radius `r`, the disk being
+    /// counted as if it were spherical
+    pub fn enclosed_mass(&self, r: f64) -> f64 {
+        let x = r / self.disk_scale_length;
+        let hernquist = |mass: f64, a: f64| mass * r * r / ((r + a) * (r + a));
+        self.disk_mass * (1.0 - (1.0 + x) * (-x).exp())
+            + hernquist(self.bulge_mass, self.bulge_scale_radius)
+            + hernquist(self.halo_mass, self.halo_scale_radius)
+            + self.black_hole.unwrap_or(0.0)
+    }
+
+    pub fn circular_velocity(&self, r: f64) -> f64 {
+        (self.g * self.enclosed_mass(r) / r).sqrt()
+    }
+
+    /// Radial velocity dispersion of the disk at radius `r`, from
+    /// Q = σ_R κ / (3.36 G Σ)
+    pub fn radial_dispersion(&self, r: f64) -> f64 {
+        self.toomre_q * 3.36 * self.g * self.surface_density(r) / self.epicyclic_frequency(r)
+    }
+
+    fn surface_density(&self, r: f64) -> f64 {
+        let rd = self.disk_scale_length;
+        self.disk_mass / (std::f64::consts::TAU * rd * rd) * (-r / rd).exp()
+    }
+
+    /// κ² = R dΩ²/dR + 4Ω²
+    fn epicyclic_frequency(&self, r: f64) -> f64 {
+        let omega2 = |r: f64| self.g * self.enclosed_mass(r) / (r * r * r);
+        let h = 1e-4 * r;
+        let slope = (omega2(r + h) - omega2(r - h)) / (2.0 * h);
+        (r * slope + 4.0 * omega2(r)).max(0.0).sqrt()
+    }
+
+    fn disk(&self, rng: &mut impl Rng) -> Vec<Body> {
+        let rd = self.disk_scale_length;
+        let z0 = self.disk_scale_height;
+        let cumulative = |x: f64| 1.0 - (1.0 + x) * (-x).exp();
+        (0..self.disk_bodies)
+            .map(|_| {
+                // Bisect the cumulative mass 1 - (1 + x) e^-x for x = R / R_d
+                let u = rng.gen_range(f64::EPSILON..cumulative(DISK_TRUNCATION));
+                let (mut low, mut high) = (0.0, DISK_TRUNCATION);
+                for _ in 0..60 {
+                    let mid = 0.5 * (low + high);
+                    if cumulative(mid) < u {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+                let r = 0.5 * (low + high) * rd;
+                let phi = rng.gen_range(0.0..std::f64::consts::TAU);
+                // Inverse of the cumulative vertical mass (1 + tanh(z / z_0)) / 2
+                let z = z0 * rng.gen_range(f64::EPSILON - 1.0..1.0).atanh();
+
+                let vc = self.circular_velocity(r);
+                let omega = vc / r;
+                let kappa = self.epicyclic_frequency(r);
+                let sigma_r = self.radial_dispersion(r);
+                let sigma_phi = sigma_r * kappa / (2.0 * omega);
+                let sigma_z = (std::f64::consts::PI * self.g * self.surface_density(r) * z0).sqrt();
+                // Mean rotation lags the circular speed by the asymmetric drift
+                let drift = 1.0 - kappa * kappa / (4.0 * omega * omega) - 2.0 * r / rd;
+                let mean = (vc * vc + sigma_r * sigma_r * drift).max(0.0).sqrt();
+                let v_phi = mean + sigma_phi * gaussian(rng);
+                let v_r = sigma_r * gaussian(rng);
+                let (cos, sin) = (phi.cos(), phi.sin());
+                Body::new(
+                    0,
+                    Vector3::new(r * cos, r * sin, z),
+                    Vector3::new(
+                        v_r * cos - v_phi * sin,
+                        v_r * sin + v_phi * cos,
+                        sigma_z * gaussian(rng),
+                    ),
+                    self.disk_mass / self.disk_bodies as f64,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Standard normal deviate by the Box-Muller transform
+fn gaussian(rng: &mut impl Rng) -> f64 {
+    let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+    (-2.0 * u.ln()).sqrt() * angle.cos()
+}
+
+/// Error function, Abramowitz and Stegun 7.1.26, good to about 1e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    1.0 - poly * (-x * x).exp()
+}
+
+/// Speed drawn from p(v) ∝ v² f(ψ - v²/2) up to escape speed, by rejection
+fn sample_speed(psi: f64, df: impl Fn(f64) -> f64, rng: &mut impl Rng) -> f64 {
+    if psi <= 0.0 {
+        return 0.0;
+    }
+    let escape = (2.0 * psi).sqrt();
+    let p = |v: f64| v * v * df(psi - v * v / 2.0);
+    // p has a single peak, bounded by its largest value on a grid with room to spare
+    let bound = (1..100)
+        .map(|i| p(escape * i as f64 / 100.0))
+        .fold(0.0, f64::max)
+        * 1.2;
+    loop {
+        let v = rng.gen_range(0.0..escape);
+        if rng.gen_range(0.0..bound) <= p(v) {
+            return v;
+        }
+    }
+}
+
+fn isotropic(rng: &mut impl Rng) -> Vector3 {
+    let z: f64 = rng.gen_range(-1.0..1.0);
+    let phi: f64 = rng.gen_range(0.0..std::f64::consts::TAU);
+    let s = (1.0 - z * z).sqrt();
+    Vector3::new(s * phi.cos(), s * phi.sin(), z)
+}
+
+/// `n` bodies at radii and speeds drawn by `draw`, in random directions,
+/// with their center of mass moved to rest at the origin
+fn sphere<R: Rng>(
+    n: u32,
+    mass: f64,
+    rng: &mut R,
+    mut draw: impl FnMut(&mut R) -> (f64, f64),
+) -> Vec<Body> {
+    let mut bodies: Vec<Body> = (0..n)
+        .map(|id| {
+            let (r, v) = draw(rng);
+            Body::new(id, isotropic(rng) * r, isotropic(rng) * v, mass / n as f64)
+        })
+        .collect();
+    to_rest(&mut bodies);
+    bodies
+}
+
+/// Move `bodies` so their center of mass is at rest at the origin
+fn to_rest(bodies: &mut [Body]) {
+    let (mass, pos, vel) = bodies
+        .iter()
+        .fold((0.0, Vector3::zero(), Vector3::zero()), |(m, p, v), b| {
+            (m + b.mass, p + b.pos * b.mass, v + b.vel * b.mass)
+        });
+    if mass > 0.0 {
+        for b in bodies.iter_mut() {
+            b.pos = b.pos - pos / mass;
+            b.vel = b.vel - vel / mass;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostics::{kinetic_energy, potential_energy},
+        softening::Softening,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Virial ratio 2K / |W| and half-mass radius
+    fn check(bodies: &[Body]) -> (f64, f64) {
+        let kinetic = kinetic_energy(bodies);
+        let potential = potential_energy(bodies, 1.0, Softening::None);
+        let mut radii: Vec<f64> = bodies.iter().map(|b| b.pos.dot(b.pos).sqrt()).collect();
+        radii.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        (2.0 * kinetic / -potential, radii[radii.len() / 2])
+    }
+
+    #[test]
+    fn test_plummer() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let bodies = plummer(3000, 10.0, 2.0, 1.0, &mut rng);
+        assert_eq!(bodies.len(), 3000);
+        assert!(bodies.iter().enumerate().all(|(i, b)| b.id == i as u32));
+        assert!((bodies.iter().map(|b| b.mass).sum::<f64>() - 10.0).abs() < 1e-9);
+        let (virial, half_mass) = check(&bodies);
+        assert!((virial - 1.0).abs() < 0.1, "virial ratio {}", virial);
+        assert!(
+            (half_mass / (1.305 * 2.0) - 1.0).abs() < 0.1,
+            "half mass {}",
+            half_mass
+        );
+    }
+
+    #[test]
+    fn test_hernquist() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let bodies = hernquist(3000, 1.0, 1.0, 1.0, &mut rng);
+        let (virial, half_mass) = check(&bodies);
+        assert!((virial - 1.0).abs() < 0.1, "virial ratio {}", virial);
+        assert!(
+            (half_mass / 2.414 - 1.0).abs() < 0.1,
+            "half mass {}",
+            half_mass
+        );
+    }
+
+    #[test]
+    fn test_king() {
+        // Tidal radius of W0 = 6 is about 20 King radii
+        let profile = KingProfile::new(6.0);
+        assert!(
+            (profile.tidal_radius() / 20.0 - 1.0).abs() < 0.1,
+            "{}",
+            profile.tidal_radius()
+        );
+
+        let mut rng = StdRng::seed_from_u64(10);
+        let bodies = king(3000, 1.0, 0.5, 6.0, 1.0, &mut rng);
+        let (virial, _) = check(&bodies);
+        assert!((virial - 1.0).abs() < 0.1, "virial ratio {}", virial);
+        let tidal = profile.tidal_radius() * 0.5;
+        assert!(bodies
+            .iter()
+            .all(|b| b.pos.dot(b.pos).sqrt() < tidal * 1.01));
+    }
+
+    #[test]
+    fn test_disk_galaxy() {
+        let mut galaxy = DiskGalaxy::new(1.0, 1.0, 2000, 1.0);
+        galaxy.black_hole = Some(0.01);
+        let mut rng = StdRng::seed_from_u64(11);
+        let bodies = galaxy.build(&mut rng);
+        assert_eq!(bodies.len(), 2001);
+        assert!(bodies.iter().enumerate().all(|(i, b)| b.id == i as u32));
+        let mass: f64 = bodies.iter().map(|b| b.mass).sum();
+        assert!((mass - (1.0 + 0.2 + 5.0 + 0.01)).abs() < 1e-9);
+        assert_eq!(bodies[2000].mass, 0.01);
+
+        // Disk bodies in an annulus around two scale lengths
+        let disk = &bodies[..galaxy.disk_bodies as usize];
+        let (mut count, mut v_phi, mut v_r2, mut sigma_r2) = (0.0, 0.0, 0.0, 0.0);
+        for b in disk.iter() {
+            let r = (b.pos.x * b.pos.x + b.pos.y * b.pos.y).sqrt();
+            if (1.5..2.5).contains(&r) {
+                let v_r = (b.pos.x * b.vel.x + b.pos.y * b.vel.y) / r;
+                count += 1.0;
+                v_phi += (b.pos.x * b.vel.y - b.pos.y * b.vel.x) / r / galaxy.circular_velocity(r);
+                v_r2 += v_r * v_r;
+                sigma_r2 += galaxy.radial_dispersion(r).powi(2);
+            }
+        }
+        assert!(count > 200.0);
+        // Rotation a little slower than circular
+        let v_phi = v_phi / count;
+        assert!(v_phi > 0.8 && v_phi < 1.0, "rotation {}", v_phi);
+        let ratio = (v_r2 / sigma_r2).sqrt();
+        assert!((ratio - 1.0).abs() < 0.15, "radial dispersion {}", ratio);
+        assert!(disk.iter().all(|b| b.pos.z.abs() < 2.0));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::{body::Body, vector::Vector3};
+
+/// Gravitational field that integrators evaluate bodies against
+pub trait Field {
+    /// Set `acc` of every body from the current positions
+    fn accelerations(&mut self, bodies: &mut [Body]);
+
+    /// Set `acc` of the bodies whose `active` is true from the current
+    /// positions of all bodies, leaving the others alone
+    fn accelerations_of(&mut self, bodies: &mut [Body], active: &[bool]) {
+        let kept: Vec<Vector3> = bodies.iter().map(|b| b.acc).collect();
+        self.accelerations(bodies);
+        for ((b, acc), active) in bodies.iter_mut().zip(kept).zip(active) {
+            if !active {
+                b.acc = acc;
+            }
+        }
+    }
+
+    /// Time derivative of the acceleration of every body, in the same order as `bodies`
+    fn jerks(&mut self, bodies: &[Body]) -> Vec<Vector3>;
+}
+
+/// Advances a set of bodies by one timestep.
+///
+/// `acc` is expected to hold the accelerations at the start of the step,
+/// and is left holding the accelerations at the end of it.
+pub trait Integrator {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field);
+
+    /// Forget anything carried over between steps, for when the bodies were
+    /// set back to an earlier state
+    fn reset(&mut self) {}
+}
+
+/// Kick-drift-kick leapfrog, equivalent to velocity verlet
+pub struct Leapfrog;
+
+impl Integrator for Leapfrog {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field) {
+        for b in bodies.iter_mut() {
+            b.vel = b.vel + b.acc * (dt * 0.5);
+            b.pos = b.pos + b.vel * dt;
+        }
+        field.accelerations(bodies);
+        for b in bodies.iter_mut() {
+            b.vel = b.vel + b.acc * (dt * 0.5);
+        }
+    }
+}
+
+/// Classic fourth order Runge-Kutta
+pub struct RungeKutta4;
+
+impl RungeKutta4 {
+    /// Move `stage` to `start` advanced by `h` along the derivatives `dx` and `dv`
+    fn stage(start: &[Body], stage: &mut [Body], dx: &[Vector3], dv: &[Vector3], h: f64) {
+        for (i, s) in stage.iter_mut().enumerate() {
+            s.pos = start[i].pos + dx[i] * h;
+            s.vel = start[i].vel + dv[i] * h;
+        }
+    }
+}
+
+impl Integrator for RungeKutta4 {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field) {
+        let start = bodies.to_vec();
+        let mut stage = bodies.to_vec();
+
+        let k1x: Vec<Vector3> = start.iter().map(|b| b.vel).collect();
+        let k1v: Vec<Vector3> = start.iter().map(|b| b.acc).collect();
+
+        RungeKutta4::stage(&start, &mut stage, &k1x, &k1v, dt * 0.5);
+        field.accelerations(&mut stage);
+        let k2x: Vec<Vector3> = stage.iter().map(|b| b.vel).collect();
+        let k2v: Vec<Vector3> = stage.iter().map(|b| b.acc).collect();
+
+        RungeKutta4::stage(&start, &mut stage, &k2x, &k2v, dt * 0.5);
+        field.accelerations(&mut stage);
+        let k3x: Vec<Vector3> = stage.iter().map(|b| b.vel).collect();
+        let k3v: Vec<Vector3> = stage.iter().map(|b| b.acc).collect();
+
+        RungeKutta4::stage(&start, &mut stage, &k3x, &k3v, dt);
+        field.accelerations(&mut stage);
+
+        for (i, b) in bodies.iter_mut().enumerate() {
+            let dx = k1x[i] + (k2x[i] + k3x[i]) * 2.0 + stage[i].vel;
+            let dv = k1v[i] + (k2v[i] + k3v[i]) * 2.0 + stage[i].acc;
+            b.pos = start[i].pos + dx * (dt / 6.0);
+            b.vel = start[i].vel + dv * (dt / 6.0);
+        }
+        field.accelerations(bodies);
+    }
+}
+
+/// Fourth order symplectic integrator of Yoshida and Forest-Ruth,
+/// built as a triple jump of leapfrog substeps
+pub struct Yoshida4;
+
+impl Integrator for Yoshida4 {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field) {
+        let cbrt2 = 2f64.powf(1.0 / 3.0);
+        let w1 = 1.0 / (2.0 - cbrt2);
+        let w0 = -cbrt2 * w1;
+        Leapfrog.step(bodies, dt * w1, field);
+        Leapfrog.step(bodies, dt * w0, field);
+        Leapfrog.step(bodies, dt * w1, field);
+    }
+}
+
+/// Fourth order Hermite predictor-corrector
+pub struct Hermite4 {
+    /// Jerks from the end of the previous step
+    jerks: Vec<Vector3>,
+}
+
+impl Hermite4 {
+    pub fn new() -> Self {
+        Hermite4 { jerks: Vec::new() }
+    }
+}
+
+impl Default for Hermite4 {
+    fn default() -> Self {
+        Hermite4::new()
+    }
+}
+
+impl Integrator for Hermite4 {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field) {
+        if self.jerks.len() != bodies.len() {
+            self.jerks = field.jerks(bodies);
+        }
+        let start = bodies.to_vec();
+
+        // Predict
+        for (i, b) in bodies.iter_mut().enumerate() {
+            let j = self.jerks[i];
+            b.pos = b.pos + b.vel * dt + b.acc * (dt * dt / 2.0) + j * (dt * dt * dt / 6.0);
+            b.vel = b.vel + b.acc * dt + j * (dt * dt / 2.0);
+        }
+
+        // Evaluate
+        field.accelerations(bodies);
+        let jerks = field.jerks(bodies);
+
+        // Correct
+        for (i, b) in bodies.iter_mut().enumerate() {
+            let s = &start[i];
+            b.vel = s.vel
+                + (s.acc + b.acc) * (dt / 2.0)
+                + (self.jerks[i] - jerks[i]) * (dt * dt / 12.0);
+            b.pos = s.pos + (s.vel + b.vel) * (dt / 2.0) + (s.acc - b.acc) * (dt * dt / 12.0);
+        }
+        self.jerks = jerks;
+    }
+
+    fn reset(&mut self) {
+        self.jerks.clear();
+    }
+}
+
+/// Hierarchical block timesteps. Every body kick-drift-kicks with its own
+/// step of `dt / 2^level`, the level picked so the step stays below
+/// `eta * |a| / |j|`. All bodies drift together, but forces are only
+/// evaluated for and kicks only given to the bodies whose step ends, and all
+/// of them are synchronised at the end of `step`. Levels carry over between
+/// steps, and are picked again from scratch when `dt` changes.
+pub struct BlockTimesteps {
+    /// Accuracy parameter of the timestep criterion
+    pub eta: f64,
+    /// Deepest level, steps are never shorter than `dt / 2^max_level`
+    pub max_level: u32,
+    /// Level of every body
+    levels: Vec<u32>,
+    /// Step the levels are relative to
+    dt: f64,
+    /// Acceleration of every body at the start of its step, to estimate jerks
+    start_acc: Vec<Vector3>,
+}
+
+impl BlockTimesteps {
+    pub fn new(eta: f64, max_level: u32) -> Self {
+        BlockTimesteps {
+            eta,
+            max_level,
+            levels: Vec::new(),
+            dt: 0.0,
+            start_acc: Vec::new(),
+        }
+    }
+
+    /// Level of every body in the last step, in the same order as the bodies
+    pub fn levels(&self) -> &[u32] {
+        &self.levels
+    }
+
+    /// Shallowest level whose step is below the timestep criterion
+    fn level(&self, dt: f64, acc: Vector3, jerk: Vector3) -> u32 {
+        let limit = self.eta * (acc.dot(acc) / jerk.dot(jerk)).sqrt();
+        // No acceleration or no jerk gives NaN or infinity
+        if limit.is_nan() || limit >= dt {
+            return 0;
+        }
+        ((dt / limit).log2().ceil() as u32).min(self.max_level)
+    }
+}
+
+impl Integrator for BlockTimesteps {
+    fn step(&mut self, bodies: &mut [Body], dt: f64, field: &mut dyn Field) {
+        if self.levels.len() != bodies.len() || self.dt != dt {
+            self.dt = dt;
+            let jerks = field.jerks(bodies);
+            self.levels = bodies
+                .iter()
+                .zip(jerks)
+                .map(|(b, j)| self.level(dt, b.acc, j))
+                .collect();
+        }
+        // Time is counted in ticks of the deepest level
+        let ticks = 1u64 << self.max_level;
+        let tick_dt = dt / ticks as f64;
+        let stride = |level: u32| ticks >> level;
+        let level_dt = |level: u32| dt / (1u64 << level) as f64;
+
+        for (b, &level) in bodies.iter_mut().zip(self.levels.iter()) {
+            b.vel = b.vel + b.acc * (level_dt(level) * 0.5);
+        }
+        self.start_acc = bodies.iter().map(|b| b.acc).collect();
+
+        let mut tick = 0;
+        let mut active = vec![false; bodies.len()];
+        while tick < ticks {
+            let next = self
+                .levels
+                .iter()
+                .map(|&level| (tick / stride(level) + 1) * stride(level))
+                .min()
+                .unwrap_or(ticks);
+            for b in bodies.iter_mut() {
+                b.pos = b.pos + b.vel * ((next - tick) as f64 * tick_dt);
+            }
+            tick = next;
+
+            for (a, &level) in active.iter_mut().zip(self.levels.iter()) {
+                *a = tick % stride(level) == 0;
+            }
+            field.accelerations_of(bodies, &active);
+            for (i, b) in bodies.iter_mut().enumerate() {
+                if !active[i] {
+                    continue;
+                }
+                let level = self.levels[i];
+                b.vel = b.vel + b.acc * (level_dt(level) * 0.5);
+
+                // Deeper at any time, shallower one level at a time when in
+                // step, and as shallow as wanted once all bodies are in step
+                let jerk = (b.acc - self.start_acc[i]) / level_dt(level);
+                let wanted = self.level(dt, b.acc, jerk);
+                self.levels[i] = if wanted >= level || tick == ticks {
+                    wanted
+                } else if tick % stride(level - 1) == 0 {
+                    level - 1
+                } else {
+                    level
+                };
+                self.start_acc[i] = b.acc;
+                if tick < ticks {
+                    b.vel = b.vel + b.acc * (level_dt(self.levels[i]) * 0.5);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.levels.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        softening::Softening,
+        solver::{direct_forces, direct_jerks},
+    };
+
+    /// Exact pairwise field with G = 1
+    struct Direct;
+
+    impl Field for Direct {
+        fn accelerations(&mut self, bodies: &mut [Body]) {
+            let forces = direct_forces(Softening::None, bodies);
+            for (b, f) in bodies.iter_mut().zip(forces) {
+                b.acc = f / b.mass;
+            }
+        }
+
+        fn jerks(&mut self, bodies: &[Body]) -> Vec<Vector3> {
+            direct_jerks(Softening::None, bodies)
+                .into_iter()
+                .zip(bodies.iter())
+                .map(|(j, b)| j / b.mass)
+                .collect()
+        }
+    }
+
+    /// Light body on a circular orbit of radius 10 around a heavy one
+    fn binary() -> Vec<Body> {
+        let v = (1000.0f64 / 10.0).sqrt();
+        vec![
+            Body::new(0, Vector3::zero(), Vector3::zero(), 1000.0),
+            Body::new(
+                1,
+                Vector3::new(10.0, 0.0, 0.0),
+                Vector3::new(0.0, v, 0.0),
+                1e-6,
+            ),
+        ]
+    }
+
+    fn orbit_radius_error(integrator: &mut dyn Integrator) -> f64 {
+        let mut bodies = binary();
+        let mut field = Direct;
+        field.accelerations(&mut bodies);
+        let mut error: f64 = 0.0;
+        for _ in 0..2000 {
+            integrator.step(&mut bodies, 0.01, &mut field);
+            let r = bodies[0].pos.distance(bodies[1].pos);
+            error = error.max((r - 10.0).abs() / 10.0);
+        }
+        error
+    }
+
+    #[test]
+    fn test_circular_orbit() {
+        assert!(orbit_radius_error(&mut Leapfrog) < 1e-3);
+        assert!(orbit_radius_error(&mut RungeKutta4) < 1e-3);
+        assert!(orbit_radius_error(&mut Yoshida4) < 1e-3);
+        assert!(orbit_radius_error(&mut BlockTimesteps::new(0.05, 8)) < 1e-3);
+        assert!(orbit_radius_error(&mut Hermite4::new()) < 1e-3);
+    }
+
+    #[test]
+    fn test_block_timesteps() {
+        // Tight inner orbit with a period of 0.2 and a wide outer one
+        let mut bodies = binary();
+        bodies[1].pos = Vector3::new(1.0, 0.0, 0.0);
+        bodies[1].vel = Vector3::new(0.0, 1000f64.sqrt(), 0.0);
+        bodies.push(Body::new(
+            2,
+            Vector3::new(0.0, 100.0, 0.0),
+            Vector3::new(-10f64.sqrt(), 0.0, 0.0),
+            1e-6,
+        ));
+        let mut field = Direct;
+        field.accelerations(&mut bodies);
+        let mut integrator = BlockTimesteps::new(0.05, 10);
+        let mut error: f64 = 0.0;
+        for _ in 0..20 {
+            integrator.step(&mut bodies, 0.1, &mut field);
+            let inner = bodies[0].pos.distance(bodies[1].pos);
+            let outer = bodies[0].pos.distance(bodies[2].pos);
+            error = error
+                .max((inner - 1.0).abs())
+                .max((outer - 100.0).abs() / 100.0);
+        }
+        assert!(error < 1e-2, "radius error {}", error);
+        let levels = integrator.levels().to_vec();
+        assert_eq!(levels[2], 0);
+        assert!(levels[1] >= 5, "levels {:?}", levels);
+
+        // Another dt picks the levels again, like a fresh start
+        let mut fresh = BlockTimesteps::new(0.05, 10);
+        let mut same = bodies.clone();
+        fresh.step(&mut same, 0.0125, &mut field);
+        integrator.step(&mut bodies, 0.0125, &mut field);
+        assert_eq!(integrator.levels(), fresh.levels());
+        assert!(integrator.levels()[1] < levels[1]);
+        assert!(bodies == same);
+    }
+}
@@ -0,0 +1,328 @@
+//! Two-body orbits: classical orbital elements and Kepler propagation.
+//!
+//! Every function takes the gravitational parameter μ = G (m1 + m2) of the
+//! pair and works on the state of a body relative to its primary.
+use std::f64::consts::TAU;
+
+use crate::{body::Body, vector::Vector3};
+
+/// Classical orbital elements, angles in radians. Parabolic orbits, with
+/// an eccentricity of exactly 1, have no finite semi-major axis and are not
+/// represented
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Elements {
+    /// Negative for hyperbolic orbits
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    /// Relative to the xy plane
+    pub inclination: f64,
+    /// Longitude of the ascending node Ω, measured from the x axis
+    pub ascending_node: f64,
+    /// Argument of periapsis ω, zero for circular orbits
+    pub argument_of_periapsis: f64,
+    /// Mean anomaly M, in [0, 2π) for elliptic orbits
+    pub mean_anomaly: f64,
+}
+
+impl Elements {
+    /// Elements of the orbit through `pos` with velocity `vel`.
+    ///
+    /// Angles that are undefined are set to zero: the ascending node of an
+    /// orbit in the xy plane, measured from the x axis instead, and the
+    /// periapsis of a circular orbit, measured from the ascending node instead
+    pub fn from_state(pos: Vector3, vel: Vector3, mu: f64) -> Elements {
+        let r = pos.dot(pos).sqrt();
+        let h = pos.cross(vel);
+        let h_norm = h.dot(h).sqrt();
+        let normal = h / h_norm;
+        let energy = vel.dot(vel) / 2.0 - mu / r;
+        let e_vec = (pos * (vel.dot(vel) - mu / r) - vel * pos.dot(vel)) / mu;
+        let e = e_vec.dot(e_vec).sqrt();
+
+        // Line of nodes, or the x axis in the xy plane
+        let node = Vector3::new(-h.y, h.x, 0.0);
+        let node_norm = node.dot(node).sqrt();
+        let node = if node_norm > 1e-12 * h_norm {
+            node / node_norm
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        // Angle from `from` to `to` about the orbit normal
+        let angle = |from: Vector3, to: Vector3| normal.dot(from.cross(to)).atan2(from.dot(to));
+
+        let (periapsis, argument) = if e > 1e-12 {
+            (e_vec / e, angle(node, e_vec).rem_euclid(TAU))
+        } else {
+            (node, 0.0)
+        };
+        let true_anomaly = angle(periapsis, pos);
+        Elements {
+            semi_major_axis: -mu / (2.0 * energy),
+            eccentricity: e,
+            inclination: (h.z / h_norm).clamp(-1.0, 1.0).acos(),
+            ascending_node: node.y.atan2(node.x).rem_euclid(TAU),
+            argument_of_periapsis: argument,
+            mean_anomaly: mean_from_true(true_anomaly, e),
+        }
+    }
+
+    /// Elements of `b` about `primary`
+    pub fn of(b: &Body, primary: &Body, g: f64) -> Elements {
+        Elements::from_state(
+            b.pos - primary.pos,
+            b.vel - primary.vel,
+            g * (b.mass + primary.mass),
+        )
+    }
+
+    /// Position and velocity on the orbit
+    pub fn to_state(&self, mu: f64) -> (Vector3, Vector3) {
+        let (a, e) = (self.semi_major_axis, self.eccentricity);
+        let nu = true_from_mean(self.mean_anomaly, e);
+        let p = a * (1.0 - e * e);
+        let r = p / (1.0 + e * nu.cos());
+        let speed = (mu / p).sqrt();
+        let pos = Vector3::new(r * nu.cos(), r * nu.sin(), 0.0);
+        let vel = Vector3::new(-nu.sin(), e + nu.cos(), 0.0) * speed;
+        (self.rotate(pos), self.rotate(vel))
+    }
+
+    /// Body of `mass` on this orbit about `primary`
+    pub fn body(&self, id: u32, mass: f64, primary: &Body, g: f64) -> Body {
+        let (pos, vel) = self.to_state(g * (mass + primary.mass));
+        Body::new(id, primary.pos + pos, primary.vel + vel, mass)
+    }
+
+    /// Orbital period, infinite for unbound orbits
+    pub fn period(&self, mu: f64) -> f64 {
+        if self.eccentricity < 1.0 {
+            TAU * (self.semi_major_axis.powi(3) / mu).sqrt()
+        } else {
+            f64::INFINITY
+        }
+    }
+
+    /// Mean motion n, the rate of change of the mean anomaly
+    pub fn mean_motion(&self, mu: f64) -> f64 {
+        (mu / self.semi_major_axis.abs().powi(3)).sqrt()
+    }
+
+    /// From the perifocal frame, periapsis along x, to the reference frame
+    fn rotate(&self, v: Vector3) -> Vector3 {
+        let (sin_w, cos_w) = self.argument_of_periapsis.sin_cos();
+        let (sin_i, cos_i) = self.inclination.sin_cos();
+        let (sin_o, cos_o) = self.ascending_node.sin_cos();
+        let (x, y) = (v.x * cos_w - v.y * sin_w, v.x * sin_w + v.y * cos_w);
+        let (y, z) = (y * cos_i, y * sin_i);
+        Vector3::new(x * cos_o - y * sin_o, x * sin_o + y * cos_o, z)
+    }
+}
+
+/// Mean anomaly of true anomaly `nu`, through the eccentric or hyperbolic anomaly
+fn mean_from_true(nu: f64, e: f64) -> f64 {
+    if e < 1.0 {
+        let big_e = 2.0 * (((1.0 - e) / (1.0 + e)).sqrt() * (nu / 2.0).tan()).atan();
+        (big_e - e * big_e.sin()).rem_euclid(TAU)
+    } else {
+        let h = 2.0 * (((e - 1.0) / (e + 1.0)).sqrt() * (nu / 2.0).tan()).atanh();
+        e * h.sinh() - h
+    }
+}
+
+/// True anomaly of mean anomaly `m`, solving Kepler's equation by Newton's method
+fn true_from_mean(m: f64, e: f64) -> f64 {
+    if e < 1.0 {
+        let m = m.rem_euclid(TAU);
+        let mut big_e = if e < 0.8 { m } else { std::f64::consts::PI };
+        for _ in 0..50 {
+            let step = (big_e - e * big_e.sin() - m) / (1.0 - e * big_e.cos());
+            big_e -= step;
+            if step.abs() < 1e-15 {
+                break;
+            }
+        }
+        2.0 * (((1.0 + e) / (1.0 - e)).sqrt() * (big_e / 2.0).tan()).atan()
+    } else {
+        let mut h = (m / e).asinh();
+        for _ in 0..50 {
+            let step = (e * h.sinh() - h - m) / (e * h.cosh() - 1.0);
+            h -= step;
+            if step.abs() < 1e-15 * h.abs().max(1.0) {
+                break;
+            }
+        }
+        2.0 * (((e + 1.0) / (e - 1.0)).sqrt() * (h / 2.0).tanh()).atan()
+    }
+}
+
+/// Stumpff functions C(z) and S(z)
+fn stumpff(z: f64) -> (f64, f64) {
+    if z.abs() < 1e-3 {
+        // Series, which avoid the cancellation of the closed forms
+        (
+            0.5 - z / 24.0 + z * z / 720.0,
+            1.0 / 6.0 - z / 120.0 + z * z / 5040.0,
+        )
+    } else if z > 0.0 {
+        let s = z.sqrt();
+        ((1.0 - s.cos()) / z, (s - s.sin()) / (s * z))
+    } else {
+        let s = (-z).sqrt();
+        ((s.cosh() - 1.0) / -z, (s.sinh() - s) / (s * -z))
+    }
+}
+
+/// State after `dt` of a body at `pos` with velocity `vel` relative to its
+/// primary, by the universal-variable formulation, valid for every kind of orbit
+pub fn propagate(pos: Vector3, vel: Vector3, mu: f64, dt: f64) -> (Vector3, Vector3) {
+    if dt == 0.0 {
+        return (pos, vel);
+    }
+    let r0 = pos.dot(pos).sqrt();
+    let rv = pos.dot(vel);
+    let sqrt_mu = mu.sqrt();
+    // Reciprocal of the semi-major axis
+    let alpha = 2.0 / r0 - vel.dot(vel) / mu;
+
+    let mut dt = dt;
+    let mut chi = if alpha > 1e-12 {
+        // Whole periods bring the body back where it started
+        let period = TAU / (alpha.powi(3) * mu).sqrt();
+        dt %= period;
+        sqrt_mu * alpha * dt
+    } else if alpha < -1e-12 {
+        let a = 1.0 / alpha;
+        let sign = dt.signum();
+        let x = (-2.0 * mu * alpha * dt) / (rv + sign * (-mu * a).sqrt() * (1.0 - r0 * alpha));
+        // The guess needs a positive argument to the logarithm, and Newton's
+        // method gets there from a cruder one otherwise
+        if x > 0.0 && x.is_finite() {
+            sign * (-a).sqrt() * x.ln()
+        } else {
+            sqrt_mu * dt * -alpha
+        }
+    } else {
+        sqrt_mu * dt / r0
+    };
+
+    // Newton's method on the universal Kepler equation
+    for _ in 0..100 {
+        let z = alpha * chi * chi;
+        let (c, s) = stumpff(z);
+        let f = rv / sqrt_mu * chi * chi * c + (1.0 - alpha * r0) * chi.powi(3) * s + r0 * chi
+            - sqrt_mu * dt;
+        let df = rv / sqrt_mu * chi * (1.0 - z * s) + (1.0 - alpha * r0) * chi * chi * c + r0;
+        let step = f / df;
+        chi -= step;
+        if step.abs() < 1e-14 * chi.abs().max(1e-300) {
+            break;
+        }
+    }
+
+    let z = alpha * chi * chi;
+    let (c, s) = stumpff(z);
+    let f = 1.0 - chi * chi / r0 * c;
+    let g = dt - chi.powi(3) * s / sqrt_mu;
+    let new_pos = pos * f + vel * g;
+    let r = new_pos.dot(new_pos).sqrt();
+    let f_dot = sqrt_mu / (r * r0) * (z * chi * s - chi);
+    let g_dot = 1.0 - chi * chi / r * c;
+    (new_pos, pos * f_dot + vel * g_dot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3, b: Vector3, tolerance: f64) {
+        let diff = a - b;
+        assert!(
+            diff.dot(diff).sqrt() <= tolerance * b.dot(b).sqrt(),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mu = 4.0 * std::f64::consts::PI * std::f64::consts::PI;
+        for &(a, e) in [(1.5, 0.3), (0.8, 0.95), (-2.0, 1.7)].iter() {
+            let elements = Elements {
+                semi_major_axis: a,
+                eccentricity: e,
+                inclination: 0.4,
+                ascending_node: 2.0,
+                argument_of_periapsis: 5.0,
+                mean_anomaly: 0.7,
+            };
+            let (pos, vel) = elements.to_state(mu);
+            let back = Elements::from_state(pos, vel, mu);
+            assert!((back.semi_major_axis / a - 1.0).abs() < 1e-10);
+            assert!((back.eccentricity - e).abs() < 1e-10);
+            assert!((back.inclination - 0.4).abs() < 1e-10);
+            assert!((back.ascending_node - 2.0).abs() < 1e-10);
+            assert!((back.argument_of_periapsis - 5.0).abs() < 1e-10);
+            assert!((back.mean_anomaly - 0.7).abs() < 1e-10);
+        }
+
+        // Circular orbit in the xy plane, at 90° from the x axis
+        let circular = Elements::from_state(
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(-1.0, 0.0, 0.0),
+            1.0,
+        );
+        assert!((circular.semi_major_axis - 1.0).abs() < 1e-12);
+        assert!(circular.eccentricity < 1e-12);
+        assert_eq!(circular.inclination, 0.0);
+        assert!((circular.mean_anomaly - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_propagate() {
+        let mu = 1.0;
+        for &(a, e) in [(1.0, 0.0), (2.0, 0.6), (-1.0, 2.5), (1.0, 0.999)].iter() {
+            let elements = Elements {
+                semi_major_axis: a,
+                eccentricity: e,
+                inclination: 1.0,
+                ascending_node: 0.5,
+                argument_of_periapsis: 1.5,
+                mean_anomaly: 0.1,
+            };
+            let (pos, vel) = elements.to_state(mu);
+            for &dt in [0.0, 0.3, -2.0, 25.0].iter() {
+                let (p, v) = propagate(pos, vel, mu, dt);
+                let later = Elements {
+                    mean_anomaly: elements.mean_anomaly + elements.mean_motion(mu) * dt,
+                    ..elements
+                };
+                let (expected_p, expected_v) = later.to_state(mu);
+                assert_close(p, expected_p, 1e-8);
+                assert_close(v, expected_v, 1e-8);
+            }
+        }
+
+        // Hyperbolic, not moved at all and moved either way
+        let pos = Vector3::new(1.0, 0.0, 0.0);
+        let vel = Vector3::new(0.0, 2.0, 0.0);
+        assert_eq!(propagate(pos, vel, mu, 0.0), (pos, vel));
+        for &dt in [1e-9, 0.5, -0.5].iter() {
+            let (p, v) = propagate(pos, vel, mu, dt);
+            let (back_p, back_v) = propagate(p, v, mu, -dt);
+            assert_close(back_p, pos, 1e-9);
+            assert_close(back_v, vel, 1e-9);
+        }
+
+        // Nearly parabolic, where elements break down
+        let pos = Vector3::new(1.0, 0.0, 0.0);
+        let vel = Vector3::new(0.0, 2f64.sqrt() * (1.0 + 1e-14), 0.0);
+        let (p, v) = propagate(pos, vel, mu, 10.0);
+        let (back_p, back_v) = propagate(p, v, mu, -10.0);
+        assert_close(back_p, pos, 1e-9);
+        assert_close(back_v, vel, 1e-9);
+        // Energy is kept
+        let energy = |p: Vector3, v: Vector3| v.dot(v) / 2.0 - mu / p.dot(p).sqrt();
+        assert!(energy(p, v).abs() < 1e-10);
+    }
+}
@@ -1,6 +1,20 @@
+pub mod accuracy;
 pub mod body;
 pub mod cube;
+pub mod diagnostics;
+pub mod ephemeris;
+#[cfg(test)]
+mod fixtures;
+pub mod fmm;
+pub mod initial_conditions;
+pub mod integrator;
+pub mod kepler;
+pub mod linear_octree;
 pub mod octree;
 mod physics_helper;
+pub mod scenario;
 pub mod simulation;
+pub mod softening;
+pub mod solver;
+pub mod units;
 pub mod vector;
@@ -0,0 +1,344 @@
+//! Octree stored as a flat list of nodes over bodies sorted by Morton key.
+//!
+//! Sorting by Z-order key puts the bodies of every node next to each other,
+//! so a node only keeps a range into the body list and the index of its first
+//! child, children being stored next to each other.
+use std::sync::OnceLock;
+
+use rayon::prelude::*;
+
+use crate::{
+    body::Body,
+    cube::Cube,
+    octree::OcTreeError,
+    physics_helper::{calc_pull, calc_pull_com, calc_pull_quadrupole, quadrupole},
+    softening::{max_length, Softening},
+    vector::Vector3,
+};
+
+/// Bits of each coordinate in a Morton key, and so the maximum depth
+const KEY_BITS: u32 = 21;
+
+pub struct Node {
+    pub boundary: Cube,
+    pub center_of_mass: Vector3,
+    pub mass: f64,
+    /// Traceless quadrupole moment about `center_of_mass`
+    pub quadrupole: [f64; 6],
+    /// Largest softening length of its bodies, None when none has its own
+    pub softening: Option<f64>,
+    /// Bodies of the node, as a range into the sorted bodies
+    pub start: usize,
+    pub end: usize,
+    /// Index of the first child, children are contiguous
+    pub first_child: usize,
+    /// Zero for leaves
+    pub child_count: usize,
+}
+
+pub struct LinearOcTree {
+    boundary: Cube,
+    /// Bodies a leaf holds before it is split
+    bucket_size: usize,
+    /// Every body with its key while `sorted` is unset, after inserts. Left
+    /// as it was once sorted, until the next insert
+    unsorted: Vec<(u64, Body)>,
+    /// Bodies sorted by key and the nodes over them, built on first use
+    sorted: OnceLock<Sorted>,
+}
+
+struct Sorted {
+    keys: Vec<u64>,
+    bodies: Vec<Body>,
+    nodes: Vec<Node>,
+}
+
+/// Spread the low 21 bits of `v` out to every third bit
+fn spread(v: u64) -> u64 {
+    let mut x = v & 0x1f_ffff;
+    x = (x | x << 32) & 0x1f_0000_0000_ffff;
+    x = (x | x << 16) & 0x1f_0000_ff00_00ff;
+    x = (x | x << 8) & 0x100f_00f0_0f00_f00f;
+    x = (x | x << 4) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x << 2) & 0x1249_2492_4924_9249;
+    x
+}
+
+impl LinearOcTree {
+    /// Tree of `bodies`, which must all be inside `boundary`
+    pub fn new(
+        boundary: Cube,
+        bucket_size: usize,
+        bodies: &[Body],
+    ) -> Result<LinearOcTree, OcTreeError> {
+        if let Some(b) = bodies.iter().find(|b| !boundary.contains(&b.pos)) {
+            return Err(OcTreeError::OutOfBounds {
+                id: b.id,
+                pos: b.pos,
+                boundary,
+            });
+        }
+        let keyed = bodies
+            .iter()
+            .map(|b| (LinearOcTree::key(&boundary, &b.pos), *b))
+            .collect();
+        let bucket_size = bucket_size.max(1);
+        Ok(LinearOcTree {
+            boundary,
+            bucket_size,
+            unsorted: Vec::new(),
+            sorted: OnceLock::from(Sorted::new(boundary, bucket_size, keyed)),
+        })
+    }
+
+    /// Insert a body. The bodies are sorted and the nodes built again on the
+    /// next query, so inserting many bodies in a row stays cheap
+    pub fn insert(&mut self, b1: Body) -> Result<(), OcTreeError> {
+        if !self.boundary.contains(&b1.pos) {
+            return Err(OcTreeError::OutOfBounds {
+                id: b1.id,
+                pos: b1.pos,
+                boundary: self.boundary,
+            });
+        }
+        if let Some(sorted) = self.sorted.take() {
+            self.unsorted = sorted.keys.into_iter().zip(sorted.bodies).collect();
+        }
+        let key = LinearOcTree::key(&self.boundary, &b1.pos);
+        self.unsorted.push((key, b1));
+        Ok(())
+    }
+
+    fn sorted(&self) -> &Sorted {
+        self.sorted
+            .get_or_init(|| Sorted::new(self.boundary, self.bucket_size, self.unsorted.clone()))
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.sorted().nodes
+    }
+
+    pub fn bodies(&self) -> &[Body] {
+        &self.sorted().bodies
+    }
+
+    /// Morton key of a position inside `boundary`, bits ordered z, y, x
+    fn key(boundary: &Cube, p: &Vector3) -> u64 {
+        let cells = (1u64 << KEY_BITS) as f64;
+        let quantize = |v: f64, min: f64| {
+            let q = ((v - min) / boundary.size * cells) as u64;
+            q.min((1 << KEY_BITS) - 1)
+        };
+        let (x, y, z) = (
+            quantize(p.x, boundary.pos.x),
+            quantize(p.y, boundary.pos.y),
+            quantize(p.z, boundary.pos.z),
+        );
+        spread(x) | spread(y) << 1 | spread(z) << 2
+    }
+
+    /// Force on `b` from the bodies in the tree, not G
+    pub fn force(&self, theta: f64, softening: Softening, b: &Body) -> Vector3 {
+        let Sorted { bodies, nodes, .. } = self.sorted();
+        let mut force = Vector3::zero();
+        if bodies.is_empty() {
+            return force;
+        }
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            let node = &nodes[id];
+            if node.child_count == 0 {
+                for other in bodies[node.start..node.end].iter() {
+                    if other.id != b.id {
+                        force = force + calc_pull(b, other, softening);
+                    }
+                }
+                continue;
+            }
+            let d = b.pos.distance(node.center_of_mass);
+            if node.boundary.size / d < theta {
+                force = force
+                    + calc_pull_com(b, node.center_of_mass, node.mass, node.softening, softening)
+                    + calc_pull_quadrupole(b, node.center_of_mass, &node.quadrupole);
+            } else {
+                stack.extend(node.first_child..node.first_child + node.child_count);
+            }
+        }
+        force
+    }
+
+    /// Force on every body from the bodies in the tree, not G
+    pub fn forces(&self, theta: f64, softening: Softening, bodies: &[Body]) -> Vec<Vector3> {
+        bodies
+            .par_iter()
+            .map(|b| self.force(theta, softening, b))
+            .collect()
+    }
+}
+
+impl Sorted {
+    /// Sort `keyed` bodies and build the nodes over them
+    fn new(boundary: Cube, bucket_size: usize, mut keyed: Vec<(u64, Body)>) -> Sorted {
+        keyed.par_sort_by_key(|(key, _)| *key);
+        let mut sorted = Sorted {
+            keys: keyed.iter().map(|(key, _)| *key).collect(),
+            bodies: keyed.into_iter().map(|(_, b)| b).collect(),
+            nodes: Vec::new(),
+        };
+        sorted
+            .nodes
+            .push(Sorted::empty_node(boundary, 0, sorted.bodies.len()));
+        if !sorted.bodies.is_empty() {
+            sorted.build(bucket_size, 0, 0);
+        }
+        sorted
+    }
+
+    fn empty_node(boundary: Cube, start: usize, end: usize) -> Node {
+        Node {
+            boundary,
+            center_of_mass: Vector3::zero(),
+            mass: 0.0,
+            quadrupole: [0.0; 6],
+            softening: None,
+            start,
+            end,
+            first_child: 0,
+            child_count: 0,
+        }
+    }
+
+    /// Split node `id` at `level` into children, then sum up its moments
+    fn build(&mut self, bucket_size: usize, id: usize, level: u32) {
+        let (start, end, boundary) = {
+            let n = &self.nodes[id];
+            (n.start, n.end, n.boundary)
+        };
+
+        if end - start > bucket_size && level < KEY_BITS {
+            // Children are the runs of equal octant bits at this level
+            let shift = 3 * (KEY_BITS - 1 - level);
+            let half = boundary.size / 2.0;
+            let first_child = self.nodes.len();
+            let mut i = start;
+            while i < end {
+                let octant = (self.keys[i] >> shift) & 7;
+                let run = self.keys[i..end].partition_point(|k| (k >> shift) & 7 == octant);
+                let offset = Vector3::new(
+                    (octant & 1) as f64,
+                    (octant >> 1 & 1) as f64,
+                    (octant >> 2 & 1) as f64,
+                ) * half;
+                let child_boundary = Cube {
+                    pos: boundary.pos + offset,
+                    size: half,
+                };
+                self.nodes
+                    .push(Sorted::empty_node(child_boundary, i, i + run));
+                i += run;
+            }
+            let child_count = self.nodes.len() - first_child;
+            self.nodes[id].first_child = first_child;
+            self.nodes[id].child_count = child_count;
+            for c in first_child..first_child + child_count {
+                self.build(bucket_size, c, level + 1);
+            }
+        }
+
+        let (mass, weighted) = self.bodies[start..end]
+            .iter()
+            .fold((0.0, Vector3::zero()), |(m, w), b| {
+                (m + b.mass, w + b.pos * b.mass)
+            });
+        let com = weighted / mass;
+        let mut quad = [0.0; 6];
+        let node = &self.nodes[id];
+        if node.child_count == 0 {
+            for b in self.bodies[start..end].iter() {
+                let q = quadrupole(b.pos - com, b.mass);
+                for (t, q) in quad.iter_mut().zip(q.iter()) {
+                    *t += q;
+                }
+            }
+        } else {
+            for child in self.nodes[node.first_child..node.first_child + node.child_count].iter() {
+                let shift = quadrupole(child.center_of_mass - com, child.mass);
+                for (i, t) in quad.iter_mut().enumerate() {
+                    *t += child.quadrupole[i] + shift[i];
+                }
+            }
+        }
+        let softening = self.bodies[start..end]
+            .iter()
+            .fold(None, |s, b| max_length(s, b.softening));
+        let node = &mut self.nodes[id];
+        node.mass = mass;
+        node.center_of_mass = com;
+        node.quadrupole = quad;
+        node.softening = softening;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, octree::OcTree, solver::barnes_hut_force, solver::direct_forces};
+
+    fn boundary() -> Cube {
+        Cube {
+            pos: Vector3::new(-50.0, -50.0, -50.0),
+            size: 100.0,
+        }
+    }
+
+    fn relative_error(a: Vector3, b: Vector3) -> f64 {
+        let diff = a - b;
+        (diff.dot(diff) / b.dot(b)).sqrt()
+    }
+
+    #[test]
+    fn test_linear_matches_direct() {
+        let bodies = fixtures::random_bodies(1000, 50.0, 5);
+        let ot = LinearOcTree::new(boundary(), 1, &bodies).unwrap();
+        let exact = direct_forces(Softening::None, &bodies);
+        for (f, e) in ot
+            .forces(0.0, Softening::None, &bodies)
+            .iter()
+            .zip(exact.iter())
+        {
+            assert!(relative_error(*f, *e) < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_linear_matches_octree() {
+        let bodies = fixtures::random_bodies(1000, 50.0, 5);
+        let boundary = boundary();
+        let linear = LinearOcTree::new(boundary, 1, &bodies).unwrap();
+        let ot = OcTree::build_parallel(boundary, 1, &bodies).unwrap();
+        for b in bodies.iter() {
+            let softening = Softening::Spline(2.0);
+            let f = linear.force(0.6, softening, b);
+            let expected = barnes_hut_force(0.6, softening, b, &ot);
+            assert!(relative_error(f, expected) < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_linear_insert() {
+        let bodies = fixtures::random_bodies(1000, 50.0, 5);
+        let boundary = boundary();
+        let built = LinearOcTree::new(boundary, 1, &bodies).unwrap();
+        let mut inserted = LinearOcTree::new(boundary, 1, &[]).unwrap();
+        for (i, b) in bodies.iter().enumerate() {
+            inserted.insert(*b).unwrap();
+            // Inserting after a query sorts the bodies again on the next one
+            if i == bodies.len() / 2 {
+                assert_eq!(inserted.bodies().len(), i + 1);
+            }
+        }
+        assert_eq!(built.nodes().len(), inserted.nodes().len());
+        let ids = |ot: &LinearOcTree| ot.bodies().iter().map(|b| b.id).collect::<Vec<u32>>();
+        assert_eq!(ids(&built), ids(&inserted));
+    }
+}
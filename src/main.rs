@@ -3,44 +3,29 @@ use ggez::{
     event::{self, EventHandler},
 };
 use ggez::{graphics, Context, ContextBuilder, GameResult};
-use n_body::{body::Body, cube::Cube, octree::OcTree, simulation::Simulation, vector::Vector3};
-use rand::Rng;
+use n_body::{
+    diagnostics::{DiagnosticsLog, PotentialMethod},
+    initial_conditions::DiskGalaxy,
+    simulation::Simulation,
+    units,
+};
 
 fn main() {
+    // A disk galaxy of 1000 bodies around a softened black hole, turning
+    // once in a few thousand steps at two scale lengths
     let mut rng = rand::thread_rng();
-    let mut bs = Vec::with_capacity(1000);
-    for i in 0..1000 {
-        let b = Body {
-            id: i,
-            pos: Vector3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0)
-                * rng.gen_range(0.0..(i as f64 + 1.0)),
-            vel: Vector3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0)
-                * rng.gen_range(0.0..(i as f64 + 1.0))
-                / 100.0,
-            acc: Vector3::zero(),
-            mass: 2_000_000.0,
-        };
-        bs.push(b);
+    let mut galaxy = DiskGalaxy::new(6e11, 100.0, 1000, units::G);
+    galaxy.black_hole = Some(6e10);
+    let mut bs = galaxy.build(&mut rng);
+    if let Some(black_hole) = bs.last_mut() {
+        black_hole.softening = Some(1.0);
     }
-    // Black hole
-    let b = Body {
-        id: 1001,
-        pos: Vector3::zero(),
-        vel: Vector3::zero(),
-        acc: Vector3::zero(),
-        mass: 2_000_000_000_000.0,
-    };
-    bs.push(b);
 
-    let sim = Simulation {
-        bodies: Box::new(bs),
-        ot: OcTree::new(Cube {
-            pos: Vector3::zero(),
-            size: 0.0,
-        }),
-        timestep: 1.0,
-        theta: 0.8,
-    };
+    let mut sim = Simulation::new(bs, 1.0, 0.8);
+    // Measured on the tree of the step, every 100 steps
+    let mut log = DiagnosticsLog::new(PotentialMethod::Tree { theta: 0.8 });
+    log.interval = 100;
+    sim.diagnostics = Some(log);
 
     let (mut ctx, mut event_loop) = ContextBuilder::new("my_game", "Cool Game Author")
         .build()
@@ -92,13 +77,9 @@ impl MyGame {
 impl EventHandler for MyGame {
     fn update(&mut self, _ctx: &mut Context) -> GameResult<()> {
         // Update code here...
-        self.sim.update();
-
-        //let mut ke: f64 = 0.0;
-        //for b in self.sim.bodies.iter() {
-        //    ke += 0.5 * b.mass * Vector2::zero().distance(b.vel).powi(2);
-        //}
-        //println!("System kinetic energy: {}", ke);
+        if let Err(e) = self.sim.update() {
+            println!("Simulation error: {}", e);
+        }
 
         //std::thread::sleep(time::Duration::from_secs(1));
         Ok(())
@@ -107,6 +88,16 @@ impl EventHandler for MyGame {
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx, graphics::BLACK);
         self.sim.draw(ctx)?;
+        if let Some(log) = &self.sim.diagnostics {
+            if let (Some(d), Some(drift)) = (log.records.last(), log.last_drift()) {
+                let text = graphics::Text::new(format!(
+                    "Energy: {:e}, relative drift: {:e}",
+                    d.energy(),
+                    drift.energy
+                ));
+                graphics::draw(ctx, &text, (ggez::mint::Point2 { x: 10.0, y: 10.0 },))?;
+            }
+        }
         graphics::present(ctx)?;
         Ok(())
     }
@@ -4,14 +4,58 @@ use ggez::{
     Context, GameResult,
 };
 
+use std::{collections::HashMap, error::Error, fmt};
+
+use rayon::prelude::*;
+
 use crate::{
     body::Body,
     cube::{Cube, Region},
     physics_helper::*,
+    softening::max_length,
     vector::Vector3,
 };
 
-/// A quadtree with a bucket size of one
+/// Bodies above which `build_parallel` builds regions concurrently
+const PARALLEL_THRESHOLD: usize = 4096;
+/// Depth at which leaves stop splitting and hold any number of bodies,
+/// so bodies at the same position do not subdivide forever
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum OcTreeError {
+    /// Body is outside the cube of the tree
+    OutOfBounds {
+        id: u32,
+        pos: Vector3,
+        boundary: Cube,
+    },
+}
+
+impl OcTreeError {
+    /// Id of the body the error is about
+    pub fn id(&self) -> u32 {
+        match self {
+            OcTreeError::OutOfBounds { id, .. } => *id,
+        }
+    }
+}
+
+impl fmt::Display for OcTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OcTreeError::OutOfBounds { id, pos, boundary } => write!(
+                f,
+                "Body {} at {:?} is outside boundary {:?}",
+                id, pos, boundary
+            ),
+        }
+    }
+}
+
+impl Error for OcTreeError {}
+
+/// An octree whose leaves hold up to a bucket size of bodies
 pub enum OcTree {
     Leaf(Leaf),
     Root(Root),
@@ -19,13 +63,48 @@ pub enum OcTree {
 
 pub struct Leaf {
     boundary: Cube,
-    pub body: Body,
+    /// Summed directly when walking the tree
+    pub bodies: Vec<Body>,
+    bucket_size: usize,
+}
+
+impl Leaf {
+    /// Mass, center of mass and quadrupole
+    fn moments(&self) -> (f64, Vector3, [f64; 6]) {
+        let (mass, weighted) = self
+            .bodies
+            .iter()
+            .fold((0.0, Vector3::zero()), |(m, w), b| {
+                (m + b.mass, w + b.pos * b.mass)
+            });
+        let com = weighted / mass;
+        let mut quad = [0.0; 6];
+        for b in self.bodies.iter() {
+            let q = quadrupole(b.pos - com, b.mass);
+            for (t, q) in quad.iter_mut().zip(q.iter()) {
+                *t += q;
+            }
+        }
+        (mass, com, quad)
+    }
+
+    fn softening(&self) -> Option<f64> {
+        self.bodies
+            .iter()
+            .fold(None, |s, b| max_length(s, b.softening))
+    }
 }
 
 pub struct Root {
     pub boundary: Cube,
     pub center_of_mass: Vector3,
     pub mass: f64,
+    /// Traceless quadrupole moment about `center_of_mass`, see `quadrupole`
+    pub quadrupole: [f64; 6],
+    /// Largest softening length of its bodies, None when none has its own
+    pub softening: Option<f64>,
+    /// Bodies a leaf holds before it is split
+    pub bucket_size: usize,
     pub tne: Option<Box<OcTree>>,
     pub tse: Option<Box<OcTree>>,
     pub tsw: Option<Box<OcTree>>,
@@ -36,12 +115,112 @@ pub struct Root {
     pub bnw: Option<Box<OcTree>>,
 }
 
+impl Root {
+    fn region_mut(&mut self, region: Region) -> &mut Option<Box<OcTree>> {
+        match region {
+            Region::TNE => &mut self.tne,
+            Region::TSE => &mut self.tse,
+            Region::TSW => &mut self.tsw,
+            Region::TNW => &mut self.tnw,
+            Region::BNE => &mut self.bne,
+            Region::BSE => &mut self.bse,
+            Region::BSW => &mut self.bsw,
+            Region::BNW => &mut self.bnw,
+        }
+    }
+
+    /// Split `bodies` into regions, build them and sum up their moments
+    fn build_regions(&mut self, bodies: Vec<Body>, depth: usize) {
+        let parallel = bodies.len() > PARALLEL_THRESHOLD;
+        let mut regions: Vec<Vec<Body>> = vec![Vec::new(); 8];
+        for b in bodies {
+            regions[self.boundary.region(&b.pos) as usize].push(b);
+        }
+
+        let boundary = self.boundary;
+        let bucket_size = self.bucket_size;
+        let build = |(region, bodies): (&Region, Vec<Body>)| {
+            let region_boundary = boundary.region_boundary(*region);
+            match bodies.len() {
+                0 => None,
+                n if n <= bucket_size || depth + 1 >= MAX_DEPTH => {
+                    Some(Box::new(OcTree::Leaf(Leaf {
+                        boundary: region_boundary,
+                        bodies,
+                        bucket_size,
+                    })))
+                }
+                _ => {
+                    let mut ot = OcTree::with_bucket_size(region_boundary, bucket_size);
+                    if let OcTree::Root(root) = &mut ot {
+                        root.build_regions(bodies, depth + 1);
+                    }
+                    Some(Box::new(ot))
+                }
+            }
+        };
+        let children: Vec<Option<Box<OcTree>>> = if parallel {
+            Region::ALL.par_iter().zip(regions).map(build).collect()
+        } else {
+            Region::ALL.iter().zip(regions).map(build).collect()
+        };
+        for (region, child) in Region::ALL.iter().zip(children) {
+            *self.region_mut(*region) = child;
+        }
+        self.sum_moments();
+    }
+
+    /// Set mass, center of mass and quadrupole from the moments of the children
+    fn sum_moments(&mut self) {
+        let (mass, weighted) = self
+            .children()
+            .map(|child| child.moments())
+            .fold((0.0, Vector3::zero()), |(m, w), (cm, ccom, _)| {
+                (m + cm, w + ccom * cm)
+            });
+        let com = if mass > 0.0 {
+            weighted / mass
+        } else {
+            Vector3::zero()
+        };
+        let mut quad = [0.0; 6];
+        for (cm, ccom, cq) in self.children().map(|child| child.moments()) {
+            let shift = quadrupole(ccom - com, cm);
+            for (i, q) in quad.iter_mut().enumerate() {
+                *q += cq[i] + shift[i];
+            }
+        }
+        self.mass = mass;
+        self.center_of_mass = com;
+        self.quadrupole = quad;
+        self.softening = self
+            .children()
+            .fold(None, |s, child| max_length(s, child.softening()));
+    }
+
+    /// Occupied regions, in the order of `Region`
+    pub fn children(&self) -> impl Iterator<Item = &OcTree> {
+        IntoIterator::into_iter([
+            &self.tne, &self.tse, &self.tsw, &self.tnw, &self.bne, &self.bse, &self.bsw, &self.bnw,
+        ])
+        .filter_map(|child| child.as_deref())
+    }
+}
+
 impl OcTree {
     pub fn new(boundary: Cube) -> OcTree {
+        OcTree::with_bucket_size(boundary, 1)
+    }
+
+    /// Empty tree whose leaves hold up to `bucket_size` bodies
+    pub fn with_bucket_size(boundary: Cube, bucket_size: usize) -> OcTree {
         OcTree::Root(Root {
-            boundary: boundary,
+            boundary,
             center_of_mass: Vector3::zero(),
             mass: 0.0,
+            quadrupole: [0.0; 6],
+            softening: None,
+            bucket_size: bucket_size.max(1),
             tne: None,
             tse: None,
             tsw: None,
@@ -53,67 +232,214 @@ impl OcTree {
         })
     }
 
-    fn subdivide(&mut self) {
+    /// Octree over a cube centered on the origin, large enough for every body
+    pub fn from_bodies(bodies: &[Body], bucket_size: usize) -> Result<OcTree, OcTreeError> {
+        OcTree::build_parallel(Cube::bounding(bodies), bucket_size, bodies)
+    }
+
+    /// Same tree as inserting every body inside `boundary` in turn, built by
+    /// splitting the bodies into regions recursively and building large regions concurrently
+    pub fn build_parallel(
+        boundary: Cube,
+        bucket_size: usize,
+        bodies: &[Body],
+    ) -> Result<OcTree, OcTreeError> {
+        if let Some(b) = bodies.iter().find(|b| !boundary.contains(&b.pos)) {
+            return Err(OcTreeError::OutOfBounds {
+                id: b.id,
+                pos: b.pos,
+                boundary,
+            });
+        }
+        let mut ot = OcTree::with_bucket_size(boundary, bucket_size);
+        if let OcTree::Root(root) = &mut ot {
+            if !bodies.is_empty() {
+                root.build_regions(bodies.to_vec(), 0);
+            }
+        }
+        Ok(ot)
+    }
+
+    /// Largest softening length of the bodies, None when none has its own
+    pub fn softening(&self) -> Option<f64> {
+        match self {
+            OcTree::Leaf(leaf) => leaf.softening(),
+            OcTree::Root(root) => root.softening,
+        }
+    }
+
+    /// Cube of the top node
+    pub fn boundary(&self) -> Cube {
         match self {
-            OcTree::Leaf(Leaf { boundary, body }) => {
-                let mut ot = OcTree::new(*boundary);
-                ot.insert(*body).ok();
-                *self = ot;
+            OcTree::Leaf(leaf) => leaf.boundary,
+            OcTree::Root(root) => root.boundary,
+        }
+    }
+
+    /// Mass, center of mass and quadrupole
+    pub fn moments(&self) -> (f64, Vector3, [f64; 6]) {
+        match self {
+            OcTree::Leaf(leaf) => leaf.moments(),
+            OcTree::Root(root) => (root.mass, root.center_of_mass, root.quadrupole),
+        }
+    }
+
+    /// Update the tree to `bodies` without rebuilding it. Bodies still inside
+    /// their leaf are updated in place, the others are removed and inserted again
+    /// and moments are summed bottom-up. Bodies are matched by id, bodies of the
+    /// tree missing from `bodies` are removed and new ones are inserted.
+    ///
+    /// Returns how many bodies were inserted. Leaves emptied by moving bodies
+    /// are removed but never merged, so the tree gets deeper than a fresh build
+    /// the more bodies move.
+    pub fn refit(&mut self, bodies: &[Body]) -> Result<usize, OcTreeError> {
+        let boundary = self.boundary();
+        if let Some(b) = bodies.iter().find(|b| !boundary.contains(&b.pos)) {
+            return Err(OcTreeError::OutOfBounds {
+                id: b.id,
+                pos: b.pos,
+                boundary,
+            });
+        }
+        let index: HashMap<u32, usize> =
+            bodies.iter().enumerate().map(|(i, b)| (b.id, i)).collect();
+        let mut seen = vec![false; bodies.len()];
+        let mut escaped = Vec::new();
+        self.refit_node(bodies, &index, &mut seen, &mut escaped);
+
+        let added = seen
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !**s)
+            .map(|(i, _)| i);
+        let moved: Vec<usize> = escaped.into_iter().chain(added).collect();
+        for &i in moved.iter() {
+            self.insert(bodies[i])?;
+        }
+        Ok(moved.len())
+    }
+
+    /// Refit below this node, collecting indices of bodies that left their leaf
+    /// in `escaped`. Returns whether the node is empty afterwards
+    fn refit_node(
+        &mut self,
+        bodies: &[Body],
+        index: &HashMap<u32, usize>,
+        seen: &mut [bool],
+        escaped: &mut Vec<usize>,
+    ) -> bool {
+        match self {
+            OcTree::Leaf(leaf) => {
+                let boundary = leaf.boundary;
+                leaf.bodies.retain_mut(|b| match index.get(&b.id) {
+                    Some(&i) => {
+                        seen[i] = true;
+                        if boundary.contains(&bodies[i].pos) {
+                            *b = bodies[i];
+                            true
+                        } else {
+                            escaped.push(i);
+                            false
+                        }
+                    }
+                    None => false,
+                });
+                leaf.bodies.is_empty()
+            }
+            OcTree::Root(root) => {
+                for region in Region::ALL.iter() {
+                    let child = root.region_mut(*region);
+                    let empty = match child {
+                        Some(ot) => ot.refit_node(bodies, index, seen, escaped),
+                        None => false,
+                    };
+                    if empty {
+                        *child = None;
+                    }
+                }
+                root.sum_moments();
+                root.children().next().is_none()
+            }
+        }
+    }
+
+    fn subdivide(&mut self, depth: usize) {
+        if let OcTree::Leaf(leaf) = self {
+            let mut ot = OcTree::with_bucket_size(leaf.boundary, leaf.bucket_size);
+            for b in leaf.bodies.iter() {
+                ot.insert_at(*b, depth).ok();
             }
-            _ => (),
+            *self = ot;
         }
     }
 
-    pub fn insert(&mut self, b1: Body) -> Result<(), &'static str> {
+    pub fn insert(&mut self, b1: Body) -> Result<(), OcTreeError> {
+        self.insert_at(b1, 0)
+    }
+
+    /// Insert into a node `depth` levels below the top
+    fn insert_at(&mut self, b1: Body, depth: usize) -> Result<(), OcTreeError> {
         match self {
-            // Occupied leaf, split into root
+            // Full leaf, split into root unless it is as deep as it goes
             OcTree::Leaf(leaf) => {
                 if leaf.boundary.contains(&b1.pos) {
-                    self.subdivide();
-                    return self.insert(b1);
+                    if leaf.bodies.len() < leaf.bucket_size || depth >= MAX_DEPTH {
+                        leaf.bodies.push(b1);
+                        return Ok(());
+                    }
+                    self.subdivide(depth);
+                    self.insert_at(b1, depth)
                 } else {
-                    println!("Tried to insert {:?}", b1.pos);
-                    println!("into {:?}", leaf.boundary);
-                    return Err("Inserted body is outside boundary");
+                    Err(OcTreeError::OutOfBounds {
+                        id: b1.id,
+                        pos: b1.pos,
+                        boundary: leaf.boundary,
+                    })
                 }
             }
             OcTree::Root(root) => {
                 if root.boundary.contains(&b1.pos) {
                     let region = root.boundary.region(&b1.pos);
-                    let node = match region {
-                        Region::TNE => &mut root.tne,
-                        Region::TSE => &mut root.tse,
-                        Region::TSW => &mut root.tsw,
-                        Region::TNW => &mut root.tnw,
-                        Region::BNE => &mut root.bne,
-                        Region::BSE => &mut root.bse,
-                        Region::BSW => &mut root.bsw,
-                        Region::BNW => &mut root.bnw,
-                    };
+                    let region_boundary = root.boundary.region_boundary(region);
+                    let bucket_size = root.bucket_size;
+                    let node = root.region_mut(region);
                     match node {
                         None => {
                             let ot = OcTree::Leaf(Leaf {
-                                boundary: root.boundary.region_boundary(region),
-                                body: b1,
+                                boundary: region_boundary,
+                                bodies: vec![b1],
+                                bucket_size,
                             });
                             *node = Some(Box::new(ot));
                         }
                         Some(ot) => {
-                            ot.insert(b1)?;
+                            ot.insert_at(b1, depth + 1)?;
                         }
                     }
-                    root.center_of_mass = calc_com(b1.pos, b1.mass, root.center_of_mass, root.mass);
+                    // Shift the quadrupole to the new center of mass, parallel axis style
+                    let com = calc_com(b1.pos, b1.mass, root.center_of_mass, root.mass);
+                    let shift = quadrupole(root.center_of_mass - com, root.mass);
+                    let added = quadrupole(b1.pos - com, b1.mass);
+                    for i in 0..6 {
+                        root.quadrupole[i] += shift[i] + added[i];
+                    }
+                    root.center_of_mass = com;
                     root.mass += b1.mass;
+                    root.softening = max_length(root.softening, b1.softening);
                     Ok(())
                 } else {
-                    return Err("Inserted body is outside boundary");
+                    Err(OcTreeError::OutOfBounds {
+                        id: b1.id,
+                        pos: b1.pos,
+                        boundary: root.boundary,
+                    })
                 }
             }
         }
     }
 
-    // Only for visualizing quad tree, expensive and inefficient.
-    // Currently only draw top and not bottom of octree, waiting on iteration over regions for legible code
+    // Only for visualizing the tree, expensive and inefficient.
+    // Draws the square of every leaf projected on the xy plane and the bodies in its bucket
     pub fn draw(&self, ctx: &mut Context) -> GameResult<()> {
         match self {
             OcTree::Leaf(leaf) => {
@@ -135,22 +461,29 @@ impl OcTree {
                     &rectangle,
                     (ggez::mint::Point2 { x: 500.0, y: 500.0 },),
                 )?;
-                // Draw body
-                let circle = graphics::Mesh::new_circle(
-                    ctx,
-                    graphics::DrawMode::fill(),
-                    Point2 {
-                        x: leaf.body.pos.x as f32,
-                        y: leaf.body.pos.y as f32,
-                    },
-                    2.0,
-                    1.0,
-                    graphics::WHITE,
-                )?;
-                graphics::draw(ctx, &circle, (ggez::mint::Point2 { x: 500.0, y: 500.0 },))?;
+                // Draw bodies
+                for body in leaf.bodies.iter() {
+                    let circle = graphics::Mesh::new_circle(
+                        ctx,
+                        graphics::DrawMode::fill(),
+                        Point2 {
+                            x: body.pos.x as f32,
+                            y: body.pos.y as f32,
+                        },
+                        2.0,
+                        1.0,
+                        graphics::WHITE,
+                    )?;
+                    graphics::draw(ctx, &circle, (ggez::mint::Point2 { x: 500.0, y: 500.0 },))?;
+                }
+                Ok(())
+            }
+            OcTree::Root(root) => {
+                for child in root.children() {
+                    child.draw(ctx)?;
+                }
                 Ok(())
             }
-            OcTree::Root(_) => Ok(()),
         }
     }
 }
@@ -158,6 +491,8 @@ impl OcTree {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixtures;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     #[test]
     fn test_octree_insert() {
@@ -165,20 +500,8 @@ mod tests {
             pos: Vector3::new(-5.0, -5.0, -5.0),
             size: 10.0,
         });
-        let b1 = Body {
-            id: 1,
-            pos: Vector3::new(4.0, -4.0, 0.0),
-            vel: Vector3::zero(),
-            acc: Vector3::zero(),
-            mass: 1.0,
-        };
-        let b2 = Body {
-            id: 2,
-            pos: Vector3::new(3.0, -4.0, 0.0),
-            vel: Vector3::zero(),
-            acc: Vector3::zero(),
-            mass: 10.0,
-        };
+        let b1 = Body::new(1, Vector3::new(4.0, -4.0, 0.0), Vector3::zero(), 1.0);
+        let b2 = Body::new(2, Vector3::new(3.0, -4.0, 0.0), Vector3::zero(), 10.0);
         assert!(ot.insert(b1).is_ok());
         assert!(ot.insert(b2).is_ok());
         assert_eq!(
@@ -190,4 +513,174 @@ mod tests {
             Vector3::new(3.090909090909091, -4.0, 0.0)
         );
     }
+
+    #[test]
+    fn test_octree_quadrupole() {
+        let mut ot = OcTree::new(Cube {
+            pos: Vector3::new(-5.0, -5.0, -5.0),
+            size: 10.0,
+        });
+        let bodies = [
+            (Vector3::new(4.0, -4.0, 1.0), 1.0),
+            (Vector3::new(-3.0, 2.0, 0.5), 10.0),
+            (Vector3::new(1.0, 3.0, -2.0), 4.0),
+            (Vector3::new(-1.0, -2.5, 3.0), 2.5),
+        ];
+        for (i, (pos, mass)) in bodies.iter().enumerate() {
+            let b = Body::new(i as u32, *pos, Vector3::zero(), *mass);
+            assert!(ot.insert(b).is_ok());
+        }
+
+        let root = match ot {
+            OcTree::Root(root) => root,
+            OcTree::Leaf(_) => panic!("Should be root"),
+        };
+        let mut expected = [0.0; 6];
+        for (pos, mass) in bodies.iter() {
+            let q = quadrupole(*pos - root.center_of_mass, *mass);
+            for (e, q) in expected.iter_mut().zip(q.iter()) {
+                *e += q;
+            }
+        }
+        for (q, e) in root.quadrupole.iter().zip(expected.iter()) {
+            assert!((q - e).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_build_parallel() {
+        let bodies = fixtures::random_bodies(10_000, 50.0, 4);
+        let boundary = Cube {
+            pos: Vector3::new(-50.0, -50.0, -50.0),
+            size: 100.0,
+        };
+
+        fn compare(a: &OcTree, b: &OcTree) {
+            let (ma, ca, qa) = a.moments();
+            let (mb, cb, qb) = b.moments();
+            assert!((ma - mb).abs() < 1e-9 * ma);
+            let d = ca - cb;
+            assert!(d.dot(d).sqrt() < 1e-9);
+            for (x, y) in qa.iter().zip(qb.iter()) {
+                assert!((x - y).abs() < 1e-6 * (1.0 + x.abs()));
+            }
+            match (a, b) {
+                (OcTree::Leaf(la), OcTree::Leaf(lb)) => {
+                    let ids = |l: &Leaf| l.bodies.iter().map(|b| b.id).collect::<Vec<u32>>();
+                    assert_eq!(ids(la), ids(lb));
+                }
+                (OcTree::Root(ra), OcTree::Root(rb)) => {
+                    assert_eq!(ra.children().count(), rb.children().count());
+                    for (x, y) in ra.children().zip(rb.children()) {
+                        compare(x, y);
+                    }
+                }
+                _ => panic!("Trees differ in shape"),
+            }
+        }
+        for &bucket_size in [1, 8].iter() {
+            let mut inserted = OcTree::with_bucket_size(boundary, bucket_size);
+            for b in bodies.iter() {
+                inserted.insert(*b).unwrap();
+            }
+            let built = OcTree::build_parallel(boundary, bucket_size, &bodies).unwrap();
+            compare(&inserted, &built);
+        }
+    }
+
+    #[test]
+    fn test_refit() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let boundary = Cube {
+            pos: Vector3::new(-50.0, -50.0, -50.0),
+            size: 100.0,
+        };
+        let mut bodies = fixtures::random_bodies(2000, 45.0, 5);
+        let mut ot = OcTree::build_parallel(boundary, 4, &bodies).unwrap();
+
+        // Move every body, drop some and add new ones
+        for b in bodies.iter_mut() {
+            b.pos = b.pos
+                + Vector3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                );
+        }
+        bodies.truncate(1900);
+        bodies.extend((2000..2050).map(|i| {
+            Body::new(
+                i,
+                Vector3::new(i as f64 / 100.0, -3.0, 7.0),
+                Vector3::zero(),
+                5.0,
+            )
+        }));
+        let moved = ot.refit(&bodies).unwrap();
+        assert!((50..1000).contains(&moved));
+
+        fn leaves<'a>(ot: &'a OcTree, depth: usize, out: &mut Vec<&'a Body>) {
+            match ot {
+                OcTree::Leaf(leaf) => {
+                    assert!(leaf.bodies.len() <= leaf.bucket_size || depth >= MAX_DEPTH);
+                    assert!(leaf.bodies.iter().all(|b| leaf.boundary.contains(&b.pos)));
+                    out.extend(leaf.bodies.iter());
+                }
+                OcTree::Root(root) => {
+                    for child in root.children() {
+                        leaves(child, depth + 1, out);
+                    }
+                }
+            }
+        }
+        let mut held = Vec::new();
+        leaves(&ot, 0, &mut held);
+        held.sort_by_key(|b| b.id);
+        assert_eq!(held.len(), bodies.len());
+        assert!(held.iter().zip(bodies.iter()).all(|(a, b)| *a == b));
+
+        let built = OcTree::build_parallel(boundary, 4, &bodies).unwrap();
+        let (ma, ca, qa) = ot.moments();
+        let (mb, cb, qb) = built.moments();
+        assert!((ma - mb).abs() < 1e-9 * ma);
+        let d = ca - cb;
+        assert!(d.dot(d).sqrt() < 1e-9);
+        for (x, y) in qa.iter().zip(qb.iter()) {
+            assert!((x - y).abs() < 1e-6 * (1.0 + x.abs()));
+        }
+    }
+
+    #[test]
+    fn test_coincident_bodies() {
+        let boundary = Cube {
+            pos: Vector3::new(-5.0, -5.0, -5.0),
+            size: 10.0,
+        };
+        let bodies: Vec<Body> = (0..3)
+            .map(|i| Body::new(i, Vector3::new(1.0, 2.0, 3.0), Vector3::zero(), 1.0))
+            .collect();
+        let mut ot = OcTree::new(boundary);
+        for b in bodies.iter() {
+            assert!(ot.insert(*b).is_ok());
+        }
+        assert_eq!(ot.moments().0, 3.0);
+        let built = OcTree::build_parallel(boundary, 1, &bodies).unwrap();
+        assert_eq!(built.moments().0, 3.0);
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let boundary = Cube {
+            pos: Vector3::new(-5.0, -5.0, -5.0),
+            size: 10.0,
+        };
+        let b = Body::new(7, Vector3::new(1.0, 2.0, 30.0), Vector3::zero(), 1.0);
+        let error = OcTreeError::OutOfBounds {
+            id: 7,
+            pos: b.pos,
+            boundary,
+        };
+        assert_eq!(OcTree::new(boundary).insert(b), Err(error));
+        assert_eq!(OcTree::build_parallel(boundary, 1, &[b]).err(), Some(error));
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{body::Body, vector::Vector3};
+use crate::{body::Body, softening::Softening, vector::Vector3};
 
 /// Calculate center of mass
 #[inline]
@@ -10,11 +10,103 @@ pub fn calc_com(pos1: Vector3, mass1: f64, pos2: Vector3, mass2: f64) -> Vector3
     )
 }
 
+/// Traceless quadrupole moment of `mass` at `offset`, as xx, yy, zz, xy, xz, yz
+#[inline]
+pub fn quadrupole(offset: Vector3, mass: f64) -> [f64; 6] {
+    let d = offset;
+    let d2 = d.dot(d);
+    [
+        mass * (3.0 * d.x * d.x - d2),
+        mass * (3.0 * d.y * d.y - d2),
+        mass * (3.0 * d.z * d.z - d2),
+        mass * 3.0 * d.x * d.y,
+        mass * 3.0 * d.x * d.z,
+        mass * 3.0 * d.y * d.z,
+    ]
+}
+
 /// Not G, which is applied last as optimization
-pub fn calc_pull(b1: &Body, b2: &Body) -> Vector3 {
-    (b2.pos - b1.pos) / (b1.pos.distance(b2.pos)).powi(3) * (b1.mass * b2.mass)
+pub fn calc_pull(b1: &Body, b2: &Body, softening: Softening) -> Vector3 {
+    calc_pull_com(b1, b2.pos, b2.mass, b2.softening, softening)
+}
+
+/// Pull of a node with its own softening length `b2_softening`, softened
+/// symmetrically with `b1`. Bodies at the same position do not pull each other
+/// when unsoftened
+pub fn calc_pull_com(
+    b1: &Body,
+    b2_pos: Vector3,
+    b2_mass: f64,
+    b2_softening: Option<f64>,
+    softening: Softening,
+) -> Vector3 {
+    let r = b2_pos - b1.pos;
+    let d2 = r.dot(r);
+    let kernel = softening.pair(b1.softening, b2_softening);
+    if d2 == 0.0 && kernel == Softening::None {
+        return Vector3::zero();
+    }
+    r * (kernel.force_factor(d2) * b1.mass * b2_mass)
+}
+
+/// Potential energy of a pair, not G. Zero for unsoftened bodies at the same
+/// position
+pub fn calc_potential(b1: &Body, b2: &Body, softening: Softening) -> f64 {
+    calc_potential_com(b1, b2.pos, b2.mass, b2.softening, softening)
+}
+
+/// Potential energy of `b1` in the monopole of a node, softened like `calc_pull_com`
+pub fn calc_potential_com(
+    b1: &Body,
+    b2_pos: Vector3,
+    b2_mass: f64,
+    b2_softening: Option<f64>,
+    softening: Softening,
+) -> f64 {
+    let r = b2_pos - b1.pos;
+    let d2 = r.dot(r);
+    let kernel = softening.pair(b1.softening, b2_softening);
+    if d2 == 0.0 && kernel == Softening::None {
+        return 0.0;
+    }
+    -b1.mass * b2_mass * kernel.potential_factor(d2)
+}
+
+/// Quadrupole correction to `calc_potential_com`, not G
+pub fn calc_potential_quadrupole(b1: &Body, com: Vector3, q: &[f64; 6]) -> f64 {
+    let r = b1.pos - com;
+    let rqr = q[0] * r.x * r.x
+        + q[1] * r.y * r.y
+        + q[2] * r.z * r.z
+        + 2.0 * (q[3] * r.x * r.y + q[4] * r.x * r.z + q[5] * r.y * r.z);
+    let d2 = r.dot(r);
+    -0.5 * rqr / (d2 * d2 * d2.sqrt()) * b1.mass
+}
+
+/// Quadrupole correction to `calc_pull_com`, not G. Never softened, nodes
+/// are only opened far beyond any softening length
+pub fn calc_pull_quadrupole(b1: &Body, com: Vector3, q: &[f64; 6]) -> Vector3 {
+    let r = b1.pos - com;
+    let qr = Vector3::new(
+        q[0] * r.x + q[3] * r.y + q[4] * r.z,
+        q[3] * r.x + q[1] * r.y + q[5] * r.z,
+        q[4] * r.x + q[5] * r.y + q[2] * r.z,
+    );
+    let d2 = b1.pos.distance_2(com);
+    let d5 = d2 * d2 * d2.sqrt();
+    (qr / d5 - r * (2.5 * r.dot(qr) / (d5 * d2))) * b1.mass
 }
 
-pub fn calc_pull_com(b1: &Body, b2_pos: Vector3, b2_mass: f64) -> Vector3 {
-    (b2_pos - b1.pos) / (b1.pos.distance(b2_pos)).powi(3) * (b1.mass * b2_mass)
+/// Time derivative of `calc_pull`, not G either. Zero for unsoftened bodies
+/// at the same position, like the pull
+pub fn calc_jerk(b1: &Body, b2: &Body, softening: Softening) -> Vector3 {
+    let r = b2.pos - b1.pos;
+    let v = b2.vel - b1.vel;
+    let d2 = r.dot(r);
+    let kernel = softening.pair(b1.softening, b2.softening);
+    if d2 == 0.0 && kernel == Softening::None {
+        return Vector3::zero();
+    }
+    (v * kernel.force_factor(d2) + r * (r.dot(v) * kernel.force_factor_slope(d2)))
+        * (b1.mass * b2.mass)
 }
@@ -0,0 +1,208 @@
+//! Encounters of two systems of bodies, such as galaxy mergers.
+use crate::{body::Body, diagnostics::center_of_mass, vector::Vector3};
+
+/// Orientation of a system relative to the orbit, which lies in the xy plane
+/// with the pericenter along x. The system is turned by `inclination` about
+/// the x axis, then by `argument` about the z axis, so a disk rotating about
+/// z is tilted by `inclination` from the orbital plane
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Orientation {
+    pub inclination: f64,
+    pub argument: f64,
+}
+
+impl Orientation {
+    pub const PROGRADE: Orientation = Orientation {
+        inclination: 0.0,
+        argument: 0.0,
+    };
+
+    fn rotate(&self, v: Vector3) -> Vector3 {
+        let (sin_i, cos_i) = self.inclination.sin_cos();
+        let (sin_w, cos_w) = self.argument.sin_cos();
+        let (y, z) = (v.y * cos_i - v.z * sin_i, v.y * sin_i + v.z * cos_i);
+        Vector3::new(v.x * cos_w - y * sin_w, v.x * sin_w + y * cos_w, z)
+    }
+}
+
+/// Two systems approaching each other on a Keplerian orbit of their centers
+/// of mass, treating each as a point of its total mass
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Merger {
+    /// Closest approach of the centers of mass
+    pub pericenter: f64,
+    /// Below 1 for a bound orbit, 1 for parabolic and above for hyperbolic
+    pub eccentricity: f64,
+    /// Distance of the centers at the start, before pericenter. Clamped to
+    /// the apocenter of bound orbits
+    pub separation: f64,
+    /// Orientations of the first and second system
+    pub orientations: [Orientation; 2],
+    /// Gravitational constant
+    pub g: f64,
+}
+
+impl Merger {
+    /// Parabolic encounter of two prograde systems
+    pub fn new(pericenter: f64, separation: f64, g: f64) -> Self {
+        Merger {
+            pericenter,
+            eccentricity: 1.0,
+            separation,
+            orientations: [Orientation::PROGRADE; 2],
+            g,
+        }
+    }
+
+    /// Position and velocity of the second system relative to the first, for
+    /// a total mass of `mass`
+    pub fn relative_orbit(&self, mass: f64) -> (Vector3, Vector3) {
+        let e = self.eccentricity;
+        let p = self.pericenter * (1.0 + e);
+        // True anomaly on the incoming branch, r = p / (1 + e cos ν)
+        let anomaly = if e > 0.0 {
+            -((p / self.separation - 1.0) / e).clamp(-1.0, 1.0).acos()
+        } else {
+            0.0
+        };
+        let (sin, cos) = anomaly.sin_cos();
+        let r = p / (1.0 + e * cos);
+        let speed = (self.g * mass / p).sqrt();
+        (
+            Vector3::new(r * cos, r * sin, 0.0),
+            Vector3::new(-sin, e + cos, 0.0) * speed,
+        )
+    }
+
+    /// Both systems placed on the orbit around their common center of mass,
+    /// which is at rest at the origin. Ids are reassigned from zero, first
+    /// system first, and `origin` is 0 for bodies of the first system and 1
+    /// for the second
+    pub fn build(&self, first: &[Body], second: &[Body]) -> Vec<Body> {
+        let mass = |bodies: &[Body]| bodies.iter().map(|b| b.mass).sum::<f64>();
+        let (m1, m2) = (mass(first), mass(second));
+        let (pos, vel) = self.relative_orbit(m1 + m2);
+        let offsets = [
+            (pos * (-m2 / (m1 + m2)), vel * (-m2 / (m1 + m2))),
+            (pos * (m1 / (m1 + m2)), vel * (m1 / (m1 + m2))),
+        ];
+
+        let mut bodies = Vec::with_capacity(first.len() + second.len());
+        for (origin, system) in [first, second].iter().enumerate() {
+            let Some(com) = center_of_mass(system) else {
+                continue;
+            };
+            let com_vel = system
+                .iter()
+                .fold(Vector3::zero(), |v, b| v + b.vel * b.mass)
+                / mass(system);
+            let orientation = &self.orientations[origin];
+            let (pos, vel) = offsets[origin];
+            bodies.extend(system.iter().map(|b| Body {
+                id: 0,
+                pos: pos + orientation.rotate(b.pos - com),
+                vel: vel + orientation.rotate(b.vel - com_vel),
+                acc: Vector3::zero(),
+                origin: origin as u32,
+                ..*b
+            }));
+        }
+        for (id, b) in bodies.iter_mut().enumerate() {
+            b.id = id as u32;
+        }
+        bodies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostics::{angular_momentum, center_of_mass_of, momentum},
+        initial_conditions::{plummer, DiskGalaxy},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Center of mass and its velocity of the bodies from `origin`
+    fn center(bodies: &[Body], origin: u32) -> (Vector3, Vector3) {
+        let system: Vec<Body> = bodies
+            .iter()
+            .filter(|b| b.origin == origin)
+            .copied()
+            .collect();
+        let mass: f64 = system.iter().map(|b| b.mass).sum();
+        (
+            center_of_mass_of(bodies, origin).unwrap(),
+            momentum(&system) / mass,
+        )
+    }
+
+    #[test]
+    fn test_parabolic_merger() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let first = plummer(200, 1.0, 0.5, 1.0, &mut rng);
+        let second: Vec<Body> = plummer(100, 0.5, 0.3, 1.0, &mut rng)
+            .into_iter()
+            .map(|b| Body {
+                pos: b.pos + Vector3::new(5.0, 5.0, 5.0),
+                ..b
+            })
+            .collect();
+        let bodies = Merger::new(1.0, 10.0, 1.0).build(&first, &second);
+        assert_eq!(bodies.len(), 300);
+        assert!(bodies.iter().enumerate().all(|(i, b)| b.id == i as u32));
+        assert!(bodies.iter().all(|b| b.origin == (b.id >= 200) as u32));
+
+        let p = momentum(&bodies);
+        assert!(p.dot(p).sqrt() < 1e-12);
+        let com = center_of_mass(&bodies).unwrap();
+        assert!(com.dot(com).sqrt() < 1e-12);
+
+        let ((x0, v0), (x1, v1)) = (center(&bodies, 0), center(&bodies, 1));
+        let (d, v) = (x1 - x0, v1 - v0);
+        assert!((d.dot(d).sqrt() - 10.0).abs() < 1e-9);
+        // Parabolic speed √(2GM / r) and angular momentum √(GM 2q)
+        assert!((v.dot(v) - 2.0 * 1.5 / 10.0).abs() < 1e-9);
+        assert!((d.cross(v).z - (1.5f64 * 2.0).sqrt()).abs() < 1e-9);
+        // Approaching
+        assert!(d.dot(v) < 0.0);
+
+        // An empty system adds no bodies and leaves the other finite
+        let alone = Merger::new(1.0, 10.0, 1.0).build(&first, &[]);
+        assert_eq!(alone.len(), 200);
+        assert!(alone
+            .iter()
+            .all(|b| b.pos.x.is_finite() && b.vel.x.is_finite()));
+    }
+
+    #[test]
+    fn test_orientation() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let galaxy = DiskGalaxy::new(1.0, 1.0, 200, 1.0).build(&mut rng);
+        let mut merger = Merger::new(2.0, 20.0, 1.0);
+        merger.eccentricity = 0.5;
+        merger.orientations[1] = Orientation {
+            inclination: std::f64::consts::FRAC_PI_2,
+            argument: 0.0,
+        };
+        let bodies = merger.build(&galaxy, &galaxy);
+        // Spin of the disk, the first 100 bodies of each galaxy
+        let spin = |origin: u32| {
+            let (x, v) = center(&bodies, origin);
+            let disk: Vec<Body> = bodies
+                .iter()
+                .filter(|b| b.origin == origin && b.id % 200 < 100)
+                .map(|b| Body {
+                    pos: b.pos - x,
+                    vel: b.vel - v,
+                    ..*b
+                })
+                .collect();
+            angular_momentum(&disk)
+        };
+        // The first disk spins about z, the second is turned to spin about -y
+        let (s0, s1) = (spin(0), spin(1));
+        assert!(s0.z > 0.0 && s0.z > 10.0 * s0.y.abs());
+        assert!(s1.y < 0.0 && -s1.y > 10.0 * s1.z.abs());
+    }
+}
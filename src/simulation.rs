@@ -4,48 +4,624 @@ use ggez::{
     Context, GameResult,
 };
 
+use std::{borrow::Cow, mem};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
 use crate::{
     body::Body,
     cube::Cube,
-    octree::OcTree,
-    physics_helper::{calc_pull, calc_pull_com},
+    diagnostics::{self, Diagnostics, DiagnosticsLog, PotentialMethod},
+    fmm::fmm_forces,
+    integrator::{Field, Integrator, Leapfrog},
+    kepler::Elements,
+    linear_octree::LinearOcTree,
+    octree::{OcTree, OcTreeError},
+    softening::Softening,
+    solver::{
+        barnes_hut_forces, direct_forces_on, direct_jerks, direct_potential_at, potential_at,
+        ForceSolver,
+    },
+    units::G,
     vector::Vector3,
 };
 
-const G: f64 = 6.6674e-11;
+/// Where the root cube of the tree is centered when fitted to the bodies
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum RootCenter {
+    Origin,
+    /// Mean position of the bodies, tighter for systems away from the origin
+    Centroid,
+}
+
+/// How `Simulation::update` picks its timestep
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TimestepControl {
+    /// Always `Simulation::timestep`
+    Fixed,
+    /// Smallest `eta * sqrt(eps / |a|)` over all bodies, eps being their
+    /// softening length, kept between `min` and `max`. Unsoftened bodies use
+    /// the distance to their nearest neighbour instead, found directly in O(N²)
+    Acceleration { eta: f64, min: f64, max: f64 },
+    /// Halve the step and redo it while the relative change in total energy,
+    /// or the absolute one from zero energy, is above `tolerance`, double it
+    /// when far below. Energy is summed directly, so this costs O(N²) every step
+    Energy { tolerance: f64, min: f64, max: f64 },
+}
+
+/// How the Barnes-Hut tree follows the bodies between force evaluations
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TreeUpdate {
+    /// Build a new tree every evaluation
+    Rebuild,
+    /// Keep the tree and only move bodies that left their leaf, see `OcTree::refit`.
+    /// Rebuilds once the bodies moved since the last rebuild exceed `max_moved`
+    /// times the number of bodies, or the root cube no longer fits
+    Refit { max_moved: f64 },
+}
+
+/// What to do with bodies outside the root cube of the tree
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum BoundsPolicy {
+    /// Grow the root cube until it holds them
+    Expand,
+    /// Remove them from the simulation into `Simulation::rejected`
+    Reject,
+    /// Return an error from `Simulation::update`
+    Report,
+}
 
 pub struct Simulation {
     pub bodies: Box<Vec<Body>>,
     pub ot: OcTree,
+    /// Step of the last update, or of the next one with a fixed timestep.
+    /// Under `TimestepControl::Energy` the step tried first next update
     pub timestep: f64,
+    pub timestep_control: TimestepControl,
+    /// Time simulated so far
+    pub time: f64,
     pub theta: f64,
+    /// Gravitational constant in the units of the bodies, see `UnitSystem::g`
+    pub g: f64,
+    pub solver: ForceSolver,
+    pub softening: Softening,
+    /// Bodies a tree leaf holds before it is split
+    pub bucket_size: usize,
+    /// Fixed root cube of the tree, fitted to the bodies every step when None
+    pub boundary: Option<Cube>,
+    pub root_center: RootCenter,
+    pub tree_update: TreeUpdate,
+    pub bounds_policy: BoundsPolicy,
+    /// Bodies removed by `BoundsPolicy::Reject`, as they were when removed
+    pub rejected: Vec<Body>,
+    pub integrator: Box<dyn Integrator>,
+    /// Worker threads for force evaluation, 0 lets rayon decide
+    pub threads: usize,
+    /// Diagnostics recorded before the first step and after every step when set
+    pub diagnostics: Option<DiagnosticsLog>,
+    /// Settings `acc` of every body was last computed with, None when the
+    /// accelerations no longer match the bodies
+    primed: Option<FieldSettings>,
+    /// Whether `ot` was built from the bodies where they are now, which is not
+    /// the case after integrators that evaluate last at predicted positions
+    tree_current: bool,
+    /// Bodies moved between leaves by refits since the tree was last rebuilt
+    moved: usize,
+    /// Thread pool and the thread count it was built for
+    pool: Option<(usize, ThreadPool)>,
+}
+
+/// Settings of `Simulation` that the accelerations depend on: G, theta,
+/// solver and softening
+type FieldSettings = (f64, f64, ForceSolver, Softening);
+
+/// Field of the configured solver, rebuilding or refitting the octree every time it is evaluated
+struct Gravity<'a> {
+    g: f64,
+    solver: ForceSolver,
+    softening: Softening,
+    theta: f64,
+    bucket_size: usize,
+    boundary: Option<Cube>,
+    root_center: RootCenter,
+    tree_update: TreeUpdate,
+    bounds_policy: BoundsPolicy,
+    pool: &'a ThreadPool,
+    /// Latest Barnes-Hut tree, starting from the previous one when refitting
+    ot: Option<OcTree>,
+    /// Positions of all bodies when `ot` was built, None until it is
+    positions: Option<Vec<Vector3>>,
+    moved: usize,
+    /// Bodies left out of the tree so far, once each
+    outside: Vec<OcTreeError>,
+}
+
+impl<'a> Gravity<'a> {
+    /// Moved bodies per body up to which the previous tree is refitted, None
+    /// when it is always rebuilt. Substeps of block timesteps, which only
+    /// evaluate some bodies, refit until every body is evaluated again
+    fn refits(&self, substep: bool) -> Option<f64> {
+        match self.tree_update {
+            TreeUpdate::Refit { max_moved } => Some(max_moved),
+            TreeUpdate::Rebuild if substep => Some(f64::INFINITY),
+            TreeUpdate::Rebuild => None,
+        }
+    }
+
+    /// Root cube of the tree, grown to every body when expanding
+    fn root(&mut self, bodies: &[Body], substep: bool) -> Cube {
+        // Keep the root of a tree that can still be refitted
+        if let (Some(_), Some(ot)) = (self.refits(substep), &self.ot) {
+            let kept = ot.boundary();
+            if self.boundary.is_none_or(|b| b == kept)
+                && bodies.iter().all(|b| kept.contains(&b.pos))
+            {
+                return kept;
+            }
+        }
+        let mut root = self.boundary.unwrap_or_else(|| match self.root_center {
+            RootCenter::Origin => Cube::bounding(bodies),
+            RootCenter::Centroid => {
+                let sum = bodies.iter().fold(Vector3::zero(), |s, b| s + b.pos);
+                Cube::bounding_around(bodies, sum / bodies.len().max(1) as f64)
+            }
+        });
+        if self.bounds_policy == BoundsPolicy::Expand {
+            for b in bodies.iter() {
+                if let Some(grown) = root.grow_to(&b.pos) {
+                    root = grown;
+                }
+            }
+            if self.boundary.is_some() {
+                self.boundary = Some(root);
+            }
+        }
+        root
+    }
+
+    /// Barnes-Hut tree of `bodies` inside `root`, refitted from the previous
+    /// tree when that is allowed and still good enough
+    fn tree(&mut self, root: Cube, bodies: &[Body], substep: bool) -> OcTree {
+        if let (Some(max_moved), Some(mut ot)) = (self.refits(substep), self.ot.take()) {
+            if ot.boundary() == root {
+                self.moved += ot
+                    .refit(bodies)
+                    .expect("Bodies were checked against the root");
+                if self.moved as f64 <= max_moved * bodies.len() as f64 {
+                    return ot;
+                }
+            }
+        }
+        self.moved = 0;
+        OcTree::build_parallel(root, self.bucket_size, bodies)
+            .expect("Bodies were checked against the root")
+    }
+}
+
+impl<'a> Gravity<'a> {
+    /// Set `acc` of the active bodies, or of all of them when `active` is None
+    fn evaluate(&mut self, bodies: &mut [Body], active: Option<&[bool]>) {
+        let (g, solver, softening, theta, bucket_size) = (
+            self.g,
+            self.solver,
+            self.softening,
+            self.theta,
+            self.bucket_size,
+        );
+        let substep = active.is_some_and(|active| active.contains(&false));
+        let root = self.root(bodies, substep);
+
+        // Bodies outside the root feel and exert no force, whatever the solver
+        for b in bodies.iter().filter(|b| !root.contains(&b.pos)) {
+            if !self.outside.iter().any(|e| e.id() == b.id) {
+                self.outside.push(OcTreeError::OutOfBounds {
+                    id: b.id,
+                    pos: b.pos,
+                    boundary: root,
+                });
+            }
+        }
+        let inside: Cow<[Body]> = if bodies.iter().all(|b| root.contains(&b.pos)) {
+            Cow::Borrowed(bodies)
+        } else {
+            Cow::Owned(
+                bodies
+                    .iter()
+                    .filter(|b| root.contains(&b.pos))
+                    .copied()
+                    .collect(),
+            )
+        };
+
+        // Bodies whose acceleration is set, forces are only computed for those inside
+        let all_inside = inside.len() == bodies.len();
+        let is_inside = |b: &Body| all_inside || root.contains(&b.pos);
+        let is_active = |i: usize| active.is_none_or(|active| active[i]);
+        let targets: Cow<[Body]> = match active {
+            None => Cow::Borrowed(&inside),
+            Some(_) => Cow::Owned(
+                bodies
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, b)| is_active(*i) && is_inside(b))
+                    .map(|(_, b)| *b)
+                    .collect(),
+            ),
+        };
+
+        let pool = self.pool;
+        let (forces, ot) = pool.install(|| match solver {
+            ForceSolver::BarnesHut => {
+                let ot = self.tree(root, &inside, substep);
+                (barnes_hut_forces(theta, softening, &targets, &ot), Some(ot))
+            }
+            ForceSolver::LinearBarnesHut => {
+                let ot = LinearOcTree::new(root, bucket_size, &inside)
+                    .expect("Bodies were checked against the root");
+                (ot.forces(theta, softening, &targets), None)
+            }
+            ForceSolver::Direct => (direct_forces_on(softening, &targets, &inside), None),
+            // Multipoles give every body its force at once, keep the targets
+            ForceSolver::Fmm { order } => {
+                let forces = fmm_forces(&inside, root, order, theta, softening)
+                    .expect("Bodies were checked against the root");
+                let inside_active = bodies
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| is_inside(b))
+                    .map(|(i, _)| is_active(i));
+                let forces = forces
+                    .into_iter()
+                    .zip(inside_active)
+                    .filter(|(_, active)| *active)
+                    .map(|(f, _)| f)
+                    .collect();
+                (forces, None)
+            }
+        });
+        if ot.is_some() {
+            self.ot = ot;
+            self.positions = Some(bodies.iter().map(|b| b.pos).collect());
+        }
+
+        let mut forces = forces.into_iter();
+        for (i, b) in bodies.iter_mut().enumerate() {
+            if !is_active(i) {
+                continue;
+            }
+            b.acc = if is_inside(b) {
+                forces.next().unwrap() / b.mass * g
+            } else {
+                Vector3::zero()
+            };
+        }
+    }
+}
+
+impl<'a> Field for Gravity<'a> {
+    fn accelerations(&mut self, bodies: &mut [Body]) {
+        self.evaluate(bodies, None);
+    }
+
+    /// Only the active bodies walk the tree, which is still built from all of them
+    fn accelerations_of(&mut self, bodies: &mut [Body], active: &[bool]) {
+        self.evaluate(bodies, Some(active));
+    }
+
+    /// Jerk is always summed directly, whatever the solver
+    fn jerks(&mut self, bodies: &[Body]) -> Vec<Vector3> {
+        let (g, softening) = (self.g, self.softening);
+        self.pool
+            .install(|| direct_jerks(softening, bodies))
+            .into_iter()
+            .zip(bodies.iter())
+            .map(|(j, b)| j / b.mass * g)
+            .collect()
+    }
 }
 
 impl Simulation {
-    pub fn update(&mut self) {
-        // Find bounds
-        let max_x: f64 = self.bodies.iter().fold(0.0, |a, &b| a.max(b.pos.x.abs()));
-        let max_y: f64 = self.bodies.iter().fold(0.0, |a, &b| a.max(b.pos.y.abs()));
-        let max_dist = max_x.max(max_y);
-
-        // Build quad tree
-        let mut ot = OcTree::new(Cube {
-            pos: Vector3::new(0.0 - max_dist, 0.0 - max_dist, 0.0 - max_dist),
-            size: max_dist * 2.0,
+    /// New Barnes-Hut simulation in SI units integrated with leapfrog, with
+    /// Plummer softening of length sqrt(0.001)
+    pub fn new(bodies: Vec<Body>, timestep: f64, theta: f64) -> Self {
+        Simulation {
+            bodies: Box::new(bodies),
+            ot: OcTree::new(Cube {
+                pos: Vector3::zero(),
+                size: 0.0,
+            }),
+            timestep,
+            timestep_control: TimestepControl::Fixed,
+            time: 0.0,
+            theta,
+            g: G,
+            solver: ForceSolver::BarnesHut,
+            softening: Softening::Plummer(0.001f64.sqrt()),
+            bucket_size: 1,
+            boundary: None,
+            root_center: RootCenter::Origin,
+            tree_update: TreeUpdate::Rebuild,
+            bounds_policy: BoundsPolicy::Expand,
+            rejected: Vec::new(),
+            integrator: Box::new(Leapfrog),
+            threads: 0,
+            diagnostics: None,
+            primed: None,
+            tree_current: false,
+            moved: 0,
+            pool: None,
+        }
+    }
+
+    /// Advance all bodies by one timestep, chosen by `timestep_control`.
+    ///
+    /// Every force evaluation drifts all bodies first, then rebuilds or refits
+    /// the tree as set by `tree_update` and computes all accelerations from
+    /// that one snapshot. Accelerations are evaluated before the first step,
+    /// and again after the settings they depend on change or `invalidate` is
+    /// called, so every step starts from a consistent state.
+    ///
+    /// Bodies outside the root cube of the tree are handled by `bounds_policy`.
+    /// Bodies that cannot be expanded to, such as ones with non-finite positions,
+    /// are reported as errors unless they are rejected. On an error the
+    /// simulation is left as it was before the call.
+    pub fn update(&mut self) -> Result<(), OcTreeError> {
+        // Kept to undo the step when it ends in an error
+        let undo = (self.bounds_policy != BoundsPolicy::Reject).then(|| {
+            (
+                (*self.bodies).clone(),
+                self.boundary,
+                self.timestep,
+                self.time,
+                self.primed,
+            )
         });
-        for b in self.bodies.iter() {
-            ot.insert(*b).ok();
+        let first_record = self
+            .diagnostics
+            .as_ref()
+            .is_some_and(|log| log.records.is_empty());
+        let mut outside = if self.primed == Some(self.field_settings()) {
+            Vec::new()
+        } else {
+            self.with_field(|_, _, _| {})
+        };
+        if outside.is_empty() || undo.is_none() {
+            if first_record {
+                self.record_diagnostics(false)?;
+            }
+            let dt = match self.timestep_control {
+                TimestepControl::Energy {
+                    tolerance,
+                    min,
+                    max,
+                } => {
+                    let (dt, stepped) = self.energy_step(tolerance, min, max);
+                    outside.extend(stepped);
+                    dt
+                }
+                control => {
+                    if let TimestepControl::Acceleration { eta, min, max } = control {
+                        self.timestep = self.acceleration_timestep(eta, min, max);
+                    }
+                    let dt = self.timestep;
+                    outside.extend(self.with_field(|bodies, integrator, field| {
+                        integrator.step(bodies, dt, field)
+                    }));
+                    dt
+                }
+            };
+            self.time += dt;
         }
 
-        // Build new position and velocity
-        for b in self.bodies.iter_mut() {
-            b.pos = b.pos + b.vel * self.timestep + b.acc * self.timestep * self.timestep * 0.5;
-            let old_acc = b.acc;
-            b.acc = Simulation::apply_forces(self.theta, &b, &ot) / b.mass * G;
-            b.vel = b.vel + (b.acc + old_acc) * (self.timestep * 0.5);
+        if !outside.is_empty() {
+            match undo {
+                // Rejecting
+                None => {
+                    let (rejected, kept): (Vec<Body>, Vec<Body>) = self
+                        .bodies
+                        .iter()
+                        .partition(|b| outside.iter().any(|e| e.id() == b.id));
+                    *self.bodies = kept;
+                    self.rejected.extend(rejected);
+                    // The bodies left still feel the rejected ones
+                    self.primed = None;
+                }
+                Some((bodies, boundary, timestep, time, primed)) => {
+                    *self.bodies = bodies;
+                    self.boundary = boundary;
+                    self.timestep = timestep;
+                    self.time = time;
+                    self.primed = primed;
+                    self.tree_current = false;
+                    self.integrator.reset();
+                    if first_record {
+                        if let Some(log) = self.diagnostics.as_mut() {
+                            log.records.clear();
+                        }
+                    }
+                    return Err(outside[0]);
+                }
+            }
         }
+        self.record_diagnostics(true)
+    }
 
-        self.ot = ot;
+    /// Recompute the accelerations before the next step. Needed after changing
+    /// `bodies`, changes to G, theta, the solver or the softening are noticed
+    pub fn invalidate(&mut self) {
+        self.primed = None;
+    }
+
+    fn field_settings(&self) -> FieldSettings {
+        (self.g, self.theta, self.solver, self.softening)
+    }
+
+    /// Potential per unit mass at `point`. The Barnes-Hut solver walks the tree
+    /// of its last force evaluation in `ot` with `theta` when that was built
+    /// from the bodies where they are now. Otherwise, and for other solvers,
+    /// which leave `ot` empty, it sums over the bodies directly
+    pub fn potential_at(&self, point: Vector3) -> f64 {
+        let potential = if self.walkable_tree() {
+            potential_at(self.theta, self.softening, point, &self.ot)
+        } else {
+            direct_potential_at(self.softening, point, &self.bodies)
+        };
+        potential * self.g
+    }
+
+    /// Speed needed at `point` to escape to infinity, see `potential_at`
+    pub fn escape_velocity(&self, point: Vector3) -> f64 {
+        (-2.0 * self.potential_at(point)).max(0.0).sqrt()
+    }
+
+    /// Orbital elements of body `id` about body `primary`, None if either is missing
+    pub fn elements(&self, id: u32, primary: u32) -> Option<Elements> {
+        let find = |id: u32| self.bodies.iter().find(|b| b.id == id);
+        Some(Elements::of(find(id)?, find(primary)?, self.g))
+    }
+
+    /// Diagnostics of the bodies now, with the softening and G of the simulation
+    pub fn measure(&self, method: PotentialMethod) -> Result<Diagnostics, OcTreeError> {
+        diagnostics::measure(&self.bodies, self.time, self.g, self.softening, method)
+    }
+
+    /// Whether `ot` is the Barnes-Hut tree of the bodies where they are now,
+    /// built with the current settings
+    fn walkable_tree(&self) -> bool {
+        self.solver == ForceSolver::BarnesHut
+            && self.primed == Some(self.field_settings())
+            && self.tree_current
+    }
+
+    /// Record diagnostics, after a step only once every `interval` steps.
+    /// Walks the tree of the simulation when it is current
+    fn record_diagnostics(&mut self, after_step: bool) -> Result<(), OcTreeError> {
+        let ot = self.walkable_tree().then_some(&self.ot);
+        if let Some(log) = self.diagnostics.as_mut() {
+            if !after_step || log.step() {
+                return log.record_with_tree(&self.bodies, self.time, self.g, self.softening, ot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` against the field of the current settings, priming accelerations
+    /// first if needed. Returns bodies found outside the tree
+    fn with_field(
+        &mut self,
+        f: impl FnOnce(&mut [Body], &mut dyn Integrator, &mut dyn Field),
+    ) -> Vec<OcTreeError> {
+        if self.pool.as_ref().is_none_or(|(n, _)| *n != self.threads) {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(self.threads)
+                .build()
+                .expect("Failed to build thread pool");
+            self.pool = Some((self.threads, pool));
+        }
+        let mut field = Gravity {
+            g: self.g,
+            solver: self.solver,
+            softening: self.softening,
+            theta: self.theta,
+            bucket_size: self.bucket_size,
+            boundary: self.boundary,
+            root_center: self.root_center,
+            tree_update: self.tree_update,
+            bounds_policy: self.bounds_policy,
+            pool: &self.pool.as_ref().unwrap().1,
+            ot: match self.tree_update {
+                TreeUpdate::Rebuild => None,
+                TreeUpdate::Refit { .. } => Some(mem::replace(
+                    &mut self.ot,
+                    OcTree::new(Cube {
+                        pos: Vector3::zero(),
+                        size: 0.0,
+                    }),
+                )),
+            },
+            positions: None,
+            moved: self.moved,
+            outside: Vec::new(),
+        };
+        let settings = self.field_settings();
+        if self.primed != Some(settings) {
+            field.accelerations(&mut self.bodies);
+            self.primed = Some(settings);
+        }
+        f(&mut self.bodies, self.integrator.as_mut(), &mut field);
+        let Gravity {
+            ot,
+            positions,
+            moved,
+            boundary,
+            outside,
+            ..
+        } = field;
+        if let Some(ot) = ot {
+            self.ot = ot;
+        }
+        if let Some(positions) = positions {
+            self.tree_current = positions.iter().eq(self.bodies.iter().map(|b| &b.pos));
+        }
+        self.boundary = boundary;
+        self.moved = moved;
+        outside
+    }
+
+    /// Smallest `eta * sqrt(eps / |a|)` over the bodies, between `min` and `max`
+    fn acceleration_timestep(&self, eta: f64, min: f64, max: f64) -> f64 {
+        let length = self.softening.length();
+        let nearest = |i: usize| {
+            self.bodies
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, o)| self.bodies[i].pos.distance(o.pos))
+                .fold(f64::INFINITY, f64::min)
+        };
+        self.bodies
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let eps = match b.softening.unwrap_or(length) {
+                    eps if eps > 0.0 => eps,
+                    _ => nearest(i),
+                };
+                eta * (eps / b.acc.dot(b.acc).sqrt()).sqrt()
+            })
+            .fold(max, f64::min)
+            .max(min)
+    }
+
+    /// Step with the largest timestep, starting from `timestep`, whose relative
+    /// energy error, absolute from zero energy, is within `tolerance`. Returns
+    /// the step taken
+    fn energy_step(&mut self, tolerance: f64, min: f64, max: f64) -> (f64, Vec<OcTreeError>) {
+        let mut dt = self.timestep.max(min).min(max);
+        let start = (*self.bodies).clone();
+        let (g, softening) = (self.g, self.softening);
+        let energy = |bodies: &[Body]| {
+            diagnostics::kinetic_energy(bodies)
+                + diagnostics::potential_energy(bodies, g, softening)
+        };
+        let before = energy(&start);
+        loop {
+            let outside =
+                self.with_field(|bodies, integrator, field| integrator.step(bodies, dt, field));
+            let after = energy(&self.bodies);
+            let error = diagnostics::relative((after - before).abs(), before.abs());
+            if error <= tolerance || dt <= min {
+                self.timestep = if error < tolerance / 8.0 {
+                    (dt * 2.0).min(max)
+                } else {
+                    dt
+                };
+                return (dt, outside);
+            }
+            *self.bodies = start.clone();
+            self.integrator.reset();
+            dt = (dt / 2.0).max(min);
+        }
     }
 
     pub fn draw(&self, ctx: &mut Context) -> GameResult<()> {
@@ -66,57 +642,434 @@ impl Simulation {
         graphics::draw(ctx, &mesh, (ggez::mint::Point2 { x: 500.0, y: 500.0 },))?;
         Ok(())
     }
+}
 
-    fn apply_forces(theta: f64, b: &Body, ot: &OcTree) -> Vector3 {
-        match ot {
-            OcTree::Leaf(leaf) => {
-                if b.id != leaf.body.id {
-                    calc_pull(b, &leaf.body)
-                } else {
-                    Vector3::zero()
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{initial_conditions::plummer, integrator::Hermite4, units::UnitSystem};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_two_body_circular_orbit() {
+        // Two equal masses 100 m apart, period of about 5440 s
+        let mass = 1e10;
+        let v = (G * mass / 200.0).sqrt();
+        let bodies = vec![
+            Body::new(
+                0,
+                Vector3::new(50.0, 0.0, 0.0),
+                Vector3::new(0.0, v, 0.0),
+                mass,
+            ),
+            Body::new(
+                1,
+                Vector3::new(-50.0, 0.0, 0.0),
+                Vector3::new(0.0, -v, 0.0),
+                mass,
+            ),
+        ];
+        let mut sim = Simulation::new(bodies, 1.0, 0.8);
+        for _ in 0..10_000 {
+            sim.update().unwrap();
+            for b in sim.bodies.iter() {
+                let r = Vector3::zero().distance(b.pos);
+                assert!((r - 50.0).abs() < 0.05, "radius drifted to {}", r);
             }
-            OcTree::Root(root) => {
-                let s = root.boundary.size;
-                let d = b.pos.distance(root.center_of_mass);
-                if s / d < theta {
-                    calc_pull_com(b, root.center_of_mass, root.mass)
-                } else {
-                    let tne = match &root.tne {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let tse = match &root.tse {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let tsw = match &root.tsw {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let tnw = match &root.tnw {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let bne = match &root.bne {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let bse = match &root.bse {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let bsw = match &root.bsw {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    let bnw = match &root.bnw {
-                        None => Vector3::zero(),
-                        Some(ot2) => Simulation::apply_forces(theta, b, ot2.as_ref()),
-                    };
-                    tne + tse + tsw + tnw + bne + bse + bsw + bnw
+        }
+    }
+
+    #[test]
+    fn test_earth_orbit_in_astronomical_units() {
+        let sun = Body::new(0, Vector3::zero(), Vector3::zero(), 1.0);
+        let earth = Body::new(
+            1,
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 2.0 * std::f64::consts::PI, 0.0),
+            3.0e-6,
+        );
+        let mut sim = Simulation::new(vec![sun, earth], 1e-3, 0.5);
+        sim.g = UnitSystem::ASTRONOMICAL.g();
+        sim.softening = Softening::None;
+        for _ in 0..1000 {
+            sim.update().unwrap();
+        }
+        // Back where it started after a year
+        let d = sim.bodies[1].pos - earth.pos;
+        assert!(d.dot(d).sqrt() < 1e-2);
+        let elements = sim.elements(1, 0).unwrap();
+        assert!((elements.semi_major_axis - 1.0).abs() < 1e-3);
+        assert!(elements.eccentricity < 1e-3);
+        assert!(sim.elements(1, 2).is_none());
+    }
+
+    /// Binary of unit masses on an orbit of eccentricity 0.9 and semi-major
+    /// axis 1 with G = 1, starting at apocentre
+    fn eccentric_binary() -> Simulation {
+        let r = 1.9;
+        let v = (2.0 * 0.1f64 / 1.9).sqrt() / 2.0;
+        let body = |id: u32, sign: f64| {
+            Body::new(
+                id,
+                Vector3::new(sign * r / 2.0, 0.0, 0.0),
+                Vector3::new(0.0, sign * v, 0.0),
+                1.0,
+            )
+        };
+        let mut sim = Simulation::new(vec![body(0, 1.0), body(1, -1.0)], 0.1, 0.5);
+        sim.g = 1.0;
+        sim.softening = Softening::Plummer(0.01);
+        sim
+    }
+
+    #[test]
+    fn test_acceleration_timestep_control() {
+        // Unsoftened bodies go by the distance between them
+        for &softening in [Softening::Plummer(0.01), Softening::None].iter() {
+            let mut sim = eccentric_binary();
+            sim.softening = softening;
+            sim.timestep_control = TimestepControl::Acceleration {
+                eta: 0.02,
+                min: 1e-6,
+                max: 0.1,
+            };
+            sim.diagnostics = Some(DiagnosticsLog::new(PotentialMethod::Exact));
+            let (mut smallest, mut largest) = (f64::MAX, 0.0f64);
+            let mut steps = 0;
+            // Two orbits of period 2π / sqrt(2)
+            while sim.time < 8.9 {
+                sim.update().unwrap();
+                smallest = smallest.min(sim.timestep);
+                largest = largest.max(sim.timestep);
+                steps += 1;
+            }
+            assert!(smallest > 1e-4, "{:?}", softening);
+            assert!(largest > 10.0 * smallest);
+
+            let log = sim.diagnostics.as_ref().unwrap();
+            assert_eq!(log.records.len(), steps + 1);
+            for drift in log.drifts() {
+                assert!(drift.energy < 1e-2, "{:?}", drift);
+                assert!(drift.momentum < 1e-12, "{:?}", drift);
+                assert!(drift.angular_momentum < 1e-9, "{:?}", drift);
+            }
+            assert!(log.last_drift().unwrap().energy < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_diagnostics_interval() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let bodies = plummer(200, 200.0, 1.0, 1.0, &mut rng);
+        let mut sim = Simulation::new(bodies, 0.01, 0.5);
+        sim.g = 1.0;
+        sim.bucket_size = 4;
+        let mut log = DiagnosticsLog::new(PotentialMethod::Tree { theta: 0.5 });
+        log.interval = 3;
+        sim.diagnostics = Some(log);
+        let mut times = vec![0.0];
+        for step in 1..=7 {
+            sim.update().unwrap();
+            if step % 3 == 0 {
+                times.push(sim.time);
+            }
+            if step == 6 {
+                // Walking the tree of the step instead of building another
+                let walked = diagnostics::tree_potential_energy(
+                    &sim.bodies,
+                    &sim.ot,
+                    0.5,
+                    sim.g,
+                    sim.softening,
+                );
+                let log = sim.diagnostics.as_ref().unwrap();
+                assert_eq!(log.records.last().unwrap().potential, walked);
+            }
+        }
+        let log = sim.diagnostics.as_ref().unwrap();
+        let recorded: Vec<f64> = log.records.iter().map(|d| d.time).collect();
+        assert_eq!(recorded, times);
+    }
+
+    #[test]
+    fn test_energy_timestep_control() {
+        let tolerance = 1e-6;
+        let mut sim = eccentric_binary();
+        sim.timestep_control = TimestepControl::Energy {
+            tolerance,
+            min: 1e-6,
+            max: 0.1,
+        };
+        let (mut smallest, mut largest) = (f64::MAX, 0.0f64);
+        while sim.time < 8.9 {
+            let before = sim.measure(PotentialMethod::Exact).unwrap().energy();
+            let time = sim.time;
+            sim.update().unwrap();
+            let after = sim.measure(PotentialMethod::Exact).unwrap().energy();
+            assert!(((after - before) / before).abs() <= tolerance);
+            smallest = smallest.min(sim.time - time);
+            largest = largest.max(sim.time - time);
+        }
+        assert!(largest > 10.0 * smallest);
+
+        // Without energy to compare to the error is absolute, a body at rest
+        // alone keeps the largest step
+        let body = Body::new(0, Vector3::zero(), Vector3::zero(), 1.0);
+        let mut sim = Simulation::new(vec![body], 0.05, 0.5);
+        sim.timestep_control = TimestepControl::Energy {
+            tolerance,
+            min: 1e-6,
+            max: 0.1,
+        };
+        sim.update().unwrap();
+        sim.update().unwrap();
+        assert_eq!(sim.time, 0.05 + 0.1);
+        assert_eq!(sim.timestep, 0.1);
+    }
+
+    #[test]
+    fn test_plummer_sphere_fits_root() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let bodies: Vec<Body> = plummer(500, 5e8, 1.0, G, &mut rng)
+            .into_iter()
+            .map(|b| Body {
+                pos: b.pos + Vector3::new(3.0, -2.0, 5.0),
+                ..b
+            })
+            .collect();
+        for &center in [RootCenter::Origin, RootCenter::Centroid].iter() {
+            let bodies = bodies.clone();
+            let mut exact = Simulation::new(bodies.clone(), 1.0, 0.0);
+            exact.solver = ForceSolver::Direct;
+            let mut sim = Simulation::new(bodies, 1.0, 0.0);
+            sim.bounds_policy = BoundsPolicy::Report;
+            sim.root_center = center;
+
+            exact.update().unwrap();
+            sim.update().unwrap();
+            for (a, b) in sim.bodies.iter().zip(exact.bodies.iter()) {
+                let diff = a.acc - b.acc;
+                assert!(diff.dot(diff) <= 1e-18 * b.acc.dot(b.acc));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_refit_matches_rebuild() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut bodies = plummer(300, 300.0, 1.0, G, &mut rng);
+        for b in bodies.iter_mut() {
+            b.mass = 1.0;
+            b.vel = Vector3::new(b.pos.y, -b.pos.x, 0.5) * 1e-3;
+        }
+        // A fixed root, the sphere reaches out to 100 scale radii
+        let boundary = Some(Cube {
+            pos: Vector3::new(-128.0, -128.0, -128.0),
+            size: 256.0,
+        });
+        let mut rebuilt = Simulation::new(bodies.clone(), 10.0, 0.0);
+        rebuilt.boundary = boundary;
+        let mut refitted = Simulation::new(bodies, 10.0, 0.0);
+        refitted.boundary = boundary;
+        refitted.tree_update = TreeUpdate::Refit { max_moved: 0.5 };
+        for _ in 0..20 {
+            rebuilt.update().unwrap();
+            refitted.update().unwrap();
+        }
+        assert!(refitted.moved > 0);
+        assert_eq!(refitted.ot.moments().0, rebuilt.ot.moments().0);
+        for (a, b) in refitted.bodies.iter().zip(rebuilt.bodies.iter()) {
+            let diff = a.pos - b.pos;
+            assert!(diff.dot(diff) < 1e-20);
+        }
+    }
+
+    #[test]
+    fn test_accelerations_of_active_bodies() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut rng = StdRng::seed_from_u64(6);
+        let bodies = plummer(200, 2e8, 1.0, G, &mut rng);
+        let active: Vec<bool> = (0..bodies.len()).map(|i| i % 3 == 0).collect();
+        for &solver in [
+            ForceSolver::BarnesHut,
+            ForceSolver::LinearBarnesHut,
+            ForceSolver::Direct,
+            ForceSolver::Fmm { order: 2 },
+        ]
+        .iter()
+        {
+            let mut field = Gravity {
+                g: G,
+                solver,
+                softening: Softening::Plummer(0.01),
+                theta: 0.5,
+                bucket_size: 1,
+                boundary: None,
+                root_center: RootCenter::Origin,
+                tree_update: TreeUpdate::Rebuild,
+                bounds_policy: BoundsPolicy::Expand,
+                pool: &pool,
+                ot: None,
+                positions: None,
+                moved: 0,
+                outside: Vec::new(),
+            };
+            let mut all = bodies.clone();
+            field.accelerations(&mut all);
+            let mut some = bodies.clone();
+            field.accelerations_of(&mut some, &active);
+            for ((a, s), active) in all.iter().zip(some.iter()).zip(active.iter()) {
+                let expected = if *active { a.acc } else { Vector3::zero() };
+                assert_eq!(s.acc, expected, "{:?}", solver);
+            }
+
+            // Substeps refit the tree of the last evaluation even when
+            // rebuilding, until every body is evaluated again
+            if solver == ForceSolver::BarnesHut {
+                for b in some.iter_mut() {
+                    b.pos = b.pos * 0.5;
                 }
+                field.accelerations_of(&mut some, &active);
+                assert!(field.moved > 0);
+                field.accelerations(&mut some);
+                assert_eq!(field.moved, 0);
             }
         }
     }
+
+    #[test]
+    fn test_potential_at() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut sim = Simulation::new(plummer(1000, 1000.0, 1.0, 1.0, &mut rng), 1.0, 0.5);
+        sim.g = 1.0;
+        // Before any tree is built, with the tree of a step, and with solvers
+        // that keep no tree
+        for &(solver, step) in [
+            (ForceSolver::BarnesHut, false),
+            (ForceSolver::BarnesHut, true),
+            (ForceSolver::Direct, true),
+            (ForceSolver::Fmm { order: 4 }, true),
+        ]
+        .iter()
+        {
+            sim.solver = solver;
+            if step {
+                sim.update().unwrap();
+            }
+            for &point in [Vector3::zero(), Vector3::new(0.5, -1.0, 2.0)].iter() {
+                let exact: f64 = sim
+                    .bodies
+                    .iter()
+                    .map(|b| -b.mass * sim.softening.potential_factor(b.pos.distance_2(point)))
+                    .sum();
+                assert!((sim.potential_at(point) / exact - 1.0).abs() < 1e-2);
+                assert_eq!(
+                    sim.escape_velocity(point),
+                    (-2.0 * sim.potential_at(point)).sqrt()
+                );
+            }
+        }
+
+        // Hermite evaluates last at predicted positions, so its tree is not
+        // walked for the corrected ones
+        sim.solver = ForceSolver::BarnesHut;
+        sim.integrator = Box::new(Hermite4::new());
+        sim.update().unwrap();
+        assert!(!sim.tree_current);
+        let point = Vector3::new(0.5, -1.0, 2.0);
+        let direct = direct_potential_at(sim.softening, point, &sim.bodies);
+        assert_eq!(sim.potential_at(point), direct * sim.g);
+        sim.integrator = Box::new(Leapfrog);
+        sim.update().unwrap();
+        assert!(sim.tree_current);
+    }
+
+    fn bounded(policy: BoundsPolicy) -> Simulation {
+        let bodies = (0..3)
+            .map(|i| Body::new(i, Vector3::new(i as f64, 0.0, 0.0), Vector3::zero(), 1.0))
+            .collect();
+        let mut sim = Simulation::new(bodies, 1.0, 0.8);
+        sim.boundary = Some(Cube {
+            pos: Vector3::new(-5.0, -5.0, -5.0),
+            size: 10.0,
+        });
+        sim.bounds_policy = policy;
+        sim.bodies[2].pos = Vector3::new(0.0, 0.0, 20.0);
+        sim
+    }
+
+    #[test]
+    fn test_settings_reprime() {
+        let run = |sim: &mut Simulation| {
+            sim.update().unwrap();
+            (*sim.bodies).clone()
+        };
+        // Changing G recomputes the accelerations, as a new simulation would
+        let mut sim = bounded(BoundsPolicy::Expand);
+        sim.boundary = None;
+        let start = run(&mut sim);
+        sim.g *= 2.0;
+        let changed = run(&mut sim);
+        let mut fresh = Simulation::new(start, 1.0, 0.8);
+        fresh.g = sim.g;
+        assert!(run(&mut fresh) == changed);
+
+        // Moved bodies are only noticed after invalidating
+        sim.bodies[0].pos = Vector3::new(-1.0, 0.0, 0.0);
+        sim.invalidate();
+        let mut fresh = Simulation::new((*sim.bodies).clone(), 1.0, 0.8);
+        fresh.g = sim.g;
+        assert!(run(&mut sim) == run(&mut fresh));
+    }
+
+    #[test]
+    fn test_bounds_policy() {
+        let mut sim = bounded(BoundsPolicy::Expand);
+        assert!(sim.update().is_ok());
+        assert!(sim.boundary.unwrap().contains(&sim.bodies[2].pos));
+
+        let mut sim = bounded(BoundsPolicy::Reject);
+        assert!(sim.update().is_ok());
+        assert_eq!(sim.bodies.len(), 2);
+        assert_eq!(sim.rejected[0].id, 2);
+
+        let mut sim = bounded(BoundsPolicy::Report);
+        match sim.update() {
+            Err(OcTreeError::OutOfBounds { id, .. }) => assert_eq!(id, 2),
+            Ok(()) => panic!("Should report body 2"),
+        }
+
+        // Direct summation keeps to the same region
+        let mut sim = bounded(BoundsPolicy::Reject);
+        sim.solver = ForceSolver::Direct;
+        assert!(sim.update().is_ok());
+        assert_eq!(sim.bodies.len(), 2);
+        assert_eq!(sim.rejected[0].id, 2);
+        let mut sim = bounded(BoundsPolicy::Report);
+        sim.solver = ForceSolver::Direct;
+        let before = (*sim.bodies).clone();
+        assert!(sim.update().is_err());
+        assert!(*sim.bodies == before);
+        let mut sim = bounded(BoundsPolicy::Expand);
+        sim.solver = ForceSolver::Direct;
+        sim.bodies[2].pos = Vector3::new(f64::NAN, 0.0, 0.0);
+        assert!(sim.update().is_err());
+
+        let mut sim = bounded(BoundsPolicy::Expand);
+        sim.bodies[2].pos = Vector3::new(f64::NAN, 0.0, 0.0);
+        assert!(sim.update().is_err());
+
+        // A body leaving during the step leaves the state as it was
+        let mut sim = bounded(BoundsPolicy::Report);
+        sim.bodies[2].pos = Vector3::new(4.0, 0.0, 0.0);
+        sim.diagnostics = Some(DiagnosticsLog::new(PotentialMethod::Exact));
+        sim.update().unwrap();
+        sim.bodies[2].vel = Vector3::new(10.0, 0.0, 0.0);
+        let before = (*sim.bodies).clone();
+        let (time, records) = (sim.time, sim.diagnostics.as_ref().unwrap().records.len());
+        assert!(sim.update().is_err());
+        assert!(*sim.bodies == before);
+        assert_eq!(sim.time, time);
+        assert_eq!(sim.diagnostics.as_ref().unwrap().records.len(), records);
+    }
 }
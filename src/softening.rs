@@ -0,0 +1,179 @@
+/// Gravitational softening, keeps close encounters from producing huge forces.
+/// Only applied to body-body and body-node pulls, never to geometric distances
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Softening {
+    /// Exact Newtonian gravity
+    None,
+    /// Plummer softening, 1 / (r² + eps²)^(3/2) with length eps
+    Plummer(f64),
+    /// Cubic spline kernel of Monaghan and Gadget, exactly Newtonian beyond
+    /// the support radius h. Matches Plummer with eps of about h / 2.8
+    Spline(f64),
+}
+
+/// Larger of two optional softening lengths
+pub fn max_length(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+impl Softening {
+    /// Softening length, zero without softening
+    pub fn length(self) -> f64 {
+        match self {
+            Softening::None => 0.0,
+            Softening::Plummer(eps) => eps,
+            Softening::Spline(h) => h,
+        }
+    }
+
+    /// Kernel between two bodies, or a body and a node, with their own
+    /// lengths. Lengths that are None are the length of `self`, and the
+    /// pair uses the larger one so both feel the same pull and momentum is
+    /// conserved. Plummer unless `self` is a spline
+    pub fn pair(self, a: Option<f64>, b: Option<f64>) -> Softening {
+        if a.is_none() && b.is_none() {
+            return self;
+        }
+        let length = a.unwrap_or(self.length()).max(b.unwrap_or(self.length()));
+        match self {
+            Softening::Spline(_) => Softening::Spline(length),
+            _ if length > 0.0 => Softening::Plummer(length),
+            _ => Softening::None,
+        }
+    }
+
+    /// Factor of the pull at squared distance `d2`, 1 / r³ without softening
+    pub fn force_factor(self, d2: f64) -> f64 {
+        match self {
+            Softening::None => 1.0 / (d2 * d2.sqrt()),
+            Softening::Plummer(eps) => {
+                let s2 = d2 + eps * eps;
+                1.0 / (s2 * s2.sqrt())
+            }
+            Softening::Spline(h) => {
+                let r = d2.sqrt();
+                if r >= h {
+                    return 1.0 / (d2 * r);
+                }
+                let u = r / h;
+                let f = if u < 0.5 {
+                    32.0 / 3.0 + u * u * (32.0 * u - 38.4)
+                } else {
+                    64.0 / 3.0 - 48.0 * u + 38.4 * u * u
+                        - 32.0 / 3.0 * u * u * u
+                        - 1.0 / (15.0 * u * u * u)
+                };
+                f / (h * h * h)
+            }
+        }
+    }
+
+    /// Factor of the potential at squared distance `d2`, 1 / r without softening
+    pub fn potential_factor(self, d2: f64) -> f64 {
+        match self {
+            Softening::None => 1.0 / d2.sqrt(),
+            Softening::Plummer(eps) => 1.0 / (d2 + eps * eps).sqrt(),
+            Softening::Spline(h) => {
+                let r = d2.sqrt();
+                if r >= h {
+                    return 1.0 / r;
+                }
+                let u = r / h;
+                let w = if u < 0.5 {
+                    2.8 - u * u * (16.0 / 3.0 + u * u * (6.4 * u - 9.6))
+                } else {
+                    3.2 - 1.0 / (15.0 * u)
+                        - u * u * (32.0 / 3.0 + u * (-16.0 + u * (9.6 - 32.0 / 15.0 * u)))
+                };
+                w / h
+            }
+        }
+    }
+
+    /// Derivative of `force_factor` with respect to distance, divided by
+    /// distance, -3 / r⁵ without softening. Needed for jerks
+    pub fn force_factor_slope(self, d2: f64) -> f64 {
+        match self {
+            Softening::None => -3.0 / (d2 * d2 * d2.sqrt()),
+            Softening::Plummer(eps) => {
+                let s2 = d2 + eps * eps;
+                -3.0 / (s2 * s2 * s2.sqrt())
+            }
+            Softening::Spline(h) => {
+                let r = d2.sqrt();
+                if r >= h {
+                    return -3.0 / (d2 * d2 * r);
+                }
+                let u = r / h;
+                let slope = if u < 0.5 {
+                    96.0 * u - 76.8
+                } else {
+                    (-48.0 + 76.8 * u - 32.0 * u * u + 0.2 / (u * u * u * u)) / u
+                };
+                slope / (h * h * h * h * h)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pair() {
+        let global = Softening::Plummer(0.1);
+        assert_eq!(global.pair(None, None), global);
+        assert_eq!(global.pair(Some(2.0), None), Softening::Plummer(2.0));
+        assert_eq!(global.pair(None, Some(0.01)), Softening::Plummer(0.1));
+        assert_eq!(
+            Softening::None.pair(Some(0.5), Some(1.5)),
+            Softening::Plummer(1.5)
+        );
+        assert_eq!(Softening::None.pair(Some(0.0), None), Softening::None);
+        assert_eq!(
+            Softening::Spline(1.0).pair(Some(3.0), None),
+            Softening::Spline(3.0)
+        );
+    }
+
+    #[test]
+    fn test_spline_kernel() {
+        let h = 2.0;
+        let spline = Softening::Spline(h);
+        // Continuous at the kernel boundaries and Newtonian outside
+        for &r in [0.5 * h, h].iter() {
+            let below = spline.force_factor((r - 1e-9) * (r - 1e-9));
+            let above = spline.force_factor((r + 1e-9) * (r + 1e-9));
+            assert!((below - above).abs() < 1e-6 * above);
+        }
+        assert_eq!(spline.force_factor(9.0), Softening::None.force_factor(9.0));
+        assert!(spline.force_factor(0.0).is_finite());
+        assert_eq!(spline.potential_factor(9.0), 1.0 / 3.0);
+
+        // Force is minus the gradient of the potential
+        for &r in [0.3, 1.4, 3.0].iter() {
+            let dr = 1e-6;
+            let numeric = (spline.potential_factor((r + dr) * (r + dr))
+                - spline.potential_factor((r - dr) * (r - dr)))
+                / (2.0 * dr);
+            let force = spline.force_factor(r * r) * r;
+            assert!((numeric + force).abs() < 1e-5 * force);
+        }
+
+        // Slope matches a finite difference of the factor
+        for &r in [0.3, 1.4, 3.0].iter() {
+            let dr = 1e-6;
+            let numeric = (spline.force_factor((r + dr) * (r + dr))
+                - spline.force_factor((r - dr) * (r - dr)))
+                / (2.0 * dr)
+                / r;
+            let slope = spline.force_factor_slope(r * r);
+            assert!((numeric - slope).abs() < 1e-5 * slope.abs());
+        }
+    }
+}
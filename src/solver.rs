@@ -0,0 +1,363 @@
+use rayon::prelude::*;
+
+use crate::{
+    body::Body,
+    octree::OcTree,
+    physics_helper::{
+        calc_jerk, calc_potential, calc_potential_com, calc_potential_quadrupole, calc_pull,
+        calc_pull_com, calc_pull_quadrupole,
+    },
+    softening::Softening,
+    vector::Vector3,
+};
+
+/// Method used to compute gravitational forces
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum ForceSolver {
+    /// Approximate O(N log N) octree walk with quadrupole nodes, controlled by theta
+    BarnesHut,
+    /// Barnes-Hut over a Morton ordered octree stored in one flat list
+    LinearBarnesHut,
+    /// Exact O(N²) pairwise summation, for validation and small N
+    Direct,
+    /// Roughly O(N) fast multipole method with multipoles up to `order`,
+    /// theta bounds the size of interacting node pairs relative to their distance
+    Fmm { order: usize },
+}
+
+/// Force on `b` from the bodies in `ot`, not G
+pub fn barnes_hut_force(theta: f64, softening: Softening, b: &Body, ot: &OcTree) -> Vector3 {
+    match ot {
+        OcTree::Leaf(leaf) => leaf
+            .bodies
+            .iter()
+            .filter(|other| other.id != b.id)
+            .fold(Vector3::zero(), |f, other| {
+                f + calc_pull(b, other, softening)
+            }),
+        OcTree::Root(root) => {
+            let s = root.boundary.size;
+            let d = b.pos.distance(root.center_of_mass);
+            if s / d < theta {
+                calc_pull_com(b, root.center_of_mass, root.mass, root.softening, softening)
+                    + calc_pull_quadrupole(b, root.center_of_mass, &root.quadrupole)
+            } else {
+                root.children().fold(Vector3::zero(), |f, ot2| {
+                    f + barnes_hut_force(theta, softening, b, ot2)
+                })
+            }
+        }
+    }
+}
+
+/// Force on every body from the bodies in `ot`, not G. Parallel over bodies,
+/// every force is summed in the same order as `barnes_hut_force`
+pub fn barnes_hut_forces(
+    theta: f64,
+    softening: Softening,
+    bodies: &[Body],
+    ot: &OcTree,
+) -> Vec<Vector3> {
+    bodies
+        .par_iter()
+        .map(|b| barnes_hut_force(theta, softening, b, ot))
+        .collect()
+}
+
+/// Potential energy of `b` in the field of the bodies in `ot`, not G.
+/// Opens the same nodes as `barnes_hut_force`
+pub fn barnes_hut_potential(theta: f64, softening: Softening, b: &Body, ot: &OcTree) -> f64 {
+    match ot {
+        OcTree::Leaf(leaf) => leaf
+            .bodies
+            .iter()
+            .filter(|other| other.id != b.id)
+            .map(|other| calc_potential(b, other, softening))
+            .sum(),
+        OcTree::Root(root) => {
+            let s = root.boundary.size;
+            let d = b.pos.distance(root.center_of_mass);
+            if s / d < theta {
+                calc_potential_com(b, root.center_of_mass, root.mass, root.softening, softening)
+                    + calc_potential_quadrupole(b, root.center_of_mass, &root.quadrupole)
+            } else {
+                root.children()
+                    .map(|ot2| barnes_hut_potential(theta, softening, b, ot2))
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Force on and potential energy of `b` in one walk, not G. Opens the same
+/// nodes as `barnes_hut_force`
+pub fn barnes_hut_force_and_potential(
+    theta: f64,
+    softening: Softening,
+    b: &Body,
+    ot: &OcTree,
+) -> (Vector3, f64) {
+    match ot {
+        OcTree::Leaf(leaf) => leaf.bodies.iter().filter(|other| other.id != b.id).fold(
+            (Vector3::zero(), 0.0),
+            |(f, p), other| {
+                (
+                    f + calc_pull(b, other, softening),
+                    p + calc_potential(b, other, softening),
+                )
+            },
+        ),
+        OcTree::Root(root) => {
+            let s = root.boundary.size;
+            let d = b.pos.distance(root.center_of_mass);
+            if s / d < theta {
+                let (com, q) = (root.center_of_mass, &root.quadrupole);
+                (
+                    calc_pull_com(b, com, root.mass, root.softening, softening)
+                        + calc_pull_quadrupole(b, com, q),
+                    calc_potential_com(b, com, root.mass, root.softening, softening)
+                        + calc_potential_quadrupole(b, com, q),
+                )
+            } else {
+                root.children().fold((Vector3::zero(), 0.0), |(f, p), ot2| {
+                    let (f2, p2) = barnes_hut_force_and_potential(theta, softening, b, ot2);
+                    (f + f2, p + p2)
+                })
+            }
+        }
+    }
+}
+
+/// Force on and potential energy of every body, not G. Parallel over bodies
+pub fn barnes_hut_forces_and_potentials(
+    theta: f64,
+    softening: Softening,
+    bodies: &[Body],
+    ot: &OcTree,
+) -> (Vec<Vector3>, Vec<f64>) {
+    bodies
+        .par_iter()
+        .map(|b| barnes_hut_force_and_potential(theta, softening, b, ot))
+        .unzip()
+}
+
+/// Potential per unit mass at `point` from the bodies in `ot`, not G.
+/// Bodies exactly at `point` are left out
+pub fn potential_at(theta: f64, softening: Softening, point: Vector3, ot: &OcTree) -> f64 {
+    let probe = probe(point);
+    match ot {
+        OcTree::Leaf(leaf) => leaf
+            .bodies
+            .iter()
+            .filter(|other| other.pos != point)
+            .map(|other| calc_potential(&probe, other, softening))
+            .sum(),
+        OcTree::Root(root) => {
+            let s = root.boundary.size;
+            let d = point.distance(root.center_of_mass);
+            if s / d < theta {
+                calc_potential_com(
+                    &probe,
+                    root.center_of_mass,
+                    root.mass,
+                    root.softening,
+                    softening,
+                ) + calc_potential_quadrupole(&probe, root.center_of_mass, &root.quadrupole)
+            } else {
+                root.children()
+                    .map(|ot2| potential_at(theta, softening, point, ot2))
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Potential per unit mass at `point` from every body in `bodies`, not G.
+/// Bodies exactly at `point` are left out
+pub fn direct_potential_at(softening: Softening, point: Vector3, bodies: &[Body]) -> f64 {
+    let probe = probe(point);
+    bodies
+        .iter()
+        .filter(|other| other.pos != point)
+        .map(|other| calc_potential(&probe, other, softening))
+        .sum()
+}
+
+/// Unsoftened unit mass at `point` feeling the potential there
+fn probe(point: Vector3) -> Body {
+    Body::new(u32::MAX, point, Vector3::zero(), 1.0)
+}
+
+/// Number of body and node interactions `barnes_hut_force` evaluates for `b`
+pub fn barnes_hut_interactions(theta: f64, b: &Body, ot: &OcTree) -> usize {
+    match ot {
+        OcTree::Leaf(leaf) => leaf.bodies.iter().filter(|other| other.id != b.id).count(),
+        OcTree::Root(root) => {
+            let s = root.boundary.size;
+            let d = b.pos.distance(root.center_of_mass);
+            if s / d < theta {
+                1
+            } else {
+                root.children()
+                    .map(|ot2| barnes_hut_interactions(theta, b, ot2))
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Force on every body from every other body, not G
+pub fn direct_forces(softening: Softening, bodies: &[Body]) -> Vec<Vector3> {
+    direct_forces_on(softening, bodies, bodies)
+}
+
+/// Force on every body of `targets` from every other body of `sources`, not G
+pub fn direct_forces_on(softening: Softening, targets: &[Body], sources: &[Body]) -> Vec<Vector3> {
+    targets
+        .par_iter()
+        .map(|b1| {
+            sources
+                .iter()
+                .filter(|b2| b2.id != b1.id)
+                .fold(Vector3::zero(), |f, b2| f + calc_pull(b1, b2, softening))
+        })
+        .collect()
+}
+
+/// Time derivative of the force on every body from every other body, not G
+pub fn direct_jerks(softening: Softening, bodies: &[Body]) -> Vec<Vector3> {
+    bodies
+        .par_iter()
+        .map(|b1| {
+            bodies
+                .iter()
+                .filter(|b2| b2.id != b1.id)
+                .fold(Vector3::zero(), |j, b2| j + calc_jerk(b1, b2, softening))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_barnes_hut_matches_direct() {
+        let bodies = fixtures::random_bodies(200, 50.0, 1);
+        let ot = OcTree::from_bodies(&bodies, 1).unwrap();
+
+        let softening = Softening::Plummer(0.5);
+        let exact = direct_forces(softening, &bodies);
+        for (b, f) in bodies.iter().zip(exact.iter()) {
+            // theta of zero opens every node
+            let approx = barnes_hut_force(0.0, softening, b, &ot);
+            let diff = *f - approx;
+            let error = (diff.dot(diff) / f.dot(*f)).sqrt();
+            assert!(error < 1e-9, "relative error {}", error);
+        }
+    }
+
+    #[test]
+    fn test_per_body_softening_conserves_momentum() {
+        let mut bodies = fixtures::random_bodies(300, 50.0, 1);
+        for (i, b) in bodies.iter_mut().enumerate() {
+            b.softening = match i % 3 {
+                0 => None,
+                1 => Some(5.0),
+                _ => Some(20.0),
+            };
+        }
+        let forces = direct_forces(Softening::Plummer(1.0), &bodies);
+        let total = forces.iter().fold(Vector3::zero(), |t, f| t + *f);
+        let scale = forces.iter().map(|f| f.dot(*f).sqrt()).sum::<f64>();
+        assert!(total.dot(total).sqrt() < 1e-12 * scale);
+
+        // Nodes are softened with the largest length of their bodies
+        let ot = OcTree::from_bodies(&bodies, 1).unwrap();
+        assert_eq!(ot.softening(), Some(20.0));
+        for (b, f) in bodies.iter().zip(forces.iter()) {
+            let diff = *f - barnes_hut_force(0.0, Softening::Plummer(1.0), b, &ot);
+            assert!(diff.dot(diff) < 1e-18 * f.dot(*f));
+        }
+    }
+
+    #[test]
+    fn test_potentials() {
+        let bodies = fixtures::random_bodies(300, 50.0, 1);
+        let softening = Softening::Plummer(0.5);
+        let ot = OcTree::from_bodies(&bodies, 1).unwrap();
+
+        for b in bodies.iter() {
+            let potential: f64 = bodies
+                .iter()
+                .filter(|other| other.id != b.id)
+                .map(|other| calc_potential(b, other, softening))
+                .sum();
+            let walked = barnes_hut_potential(0.0, softening, b, &ot);
+            assert!((walked - potential).abs() < 1e-9 * potential.abs());
+            // A body at a point takes no part in the potential there
+            let at = potential_at(0.0, softening, b.pos, &ot) * b.mass;
+            assert!((at - potential).abs() < 1e-9 * potential.abs());
+            let at = direct_potential_at(softening, b.pos, &bodies) * b.mass;
+            assert!((at - potential).abs() < 1e-9 * potential.abs());
+        }
+
+        // One walk gives the same forces and potentials as two
+        let (forces, potentials) = barnes_hut_forces_and_potentials(0.0, softening, &bodies, &ot);
+        let exact = direct_forces(softening, &bodies);
+        for (i, b) in bodies.iter().enumerate() {
+            let diff = forces[i] - exact[i];
+            assert!(diff.dot(diff) < 1e-18 * exact[i].dot(exact[i]));
+            let potential = direct_potential_at(softening, b.pos, &bodies) * b.mass;
+            assert!((potentials[i] - potential).abs() < 1e-9 * potential.abs());
+        }
+        let (forces, potentials) = barnes_hut_forces_and_potentials(0.8, softening, &bodies, &ot);
+        for (i, b) in bodies.iter().enumerate() {
+            assert_eq!(forces[i], barnes_hut_force(0.8, softening, b, &ot));
+            assert_eq!(potentials[i], barnes_hut_potential(0.8, softening, b, &ot));
+        }
+
+        // Far away it is the potential of a point mass
+        let mass: f64 = bodies.iter().map(|b| b.mass).sum();
+        let (_, com, _) = ot.moments();
+        let far = com + Vector3::new(1e5, 0.0, 0.0);
+        let approx = potential_at(0.8, softening, far, &ot);
+        assert!((approx + mass / 1e5).abs() < 1e-6 * mass / 1e5);
+
+        // Softened bodies at the same position are bound but do not pull
+        let (a, b) = (
+            bodies[0],
+            Body {
+                id: 300,
+                ..bodies[0]
+            },
+        );
+        let potential = -a.mass * b.mass / 0.5;
+        assert!((calc_potential(&a, &b, softening) / potential - 1.0).abs() < 1e-12);
+        assert_eq!(calc_pull(&a, &b, softening), Vector3::zero());
+        assert_eq!(calc_potential(&a, &b, Softening::None), 0.0);
+        // but start to once they move apart
+        let b = Body {
+            vel: a.vel + Vector3::new(1.0, 0.0, 0.0),
+            ..b
+        };
+        let jerk = Vector3::new(1.0, 0.0, 0.0) * (softening.force_factor(0.0) * a.mass * b.mass);
+        assert_eq!(calc_jerk(&a, &b, softening), jerk);
+        assert_eq!(calc_jerk(&a, &b, Softening::None), Vector3::zero());
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let bodies = fixtures::random_bodies(2000, 50.0, 1);
+        let ot = OcTree::from_bodies(&bodies, 1).unwrap();
+        let serial: Vec<Vector3> = bodies
+            .iter()
+            .map(|b| barnes_hut_force(0.8, Softening::None, b, &ot))
+            .collect();
+        assert_eq!(
+            serial,
+            barnes_hut_forces(0.8, Softening::None, &bodies, &ot)
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::body::Body;
+
+/// Gravitational constant in SI units, m³ / (kg s²)
+pub const G: f64 = 6.6743e-11;
+
+/// Astronomical unit in metres
+pub const AU: f64 = 1.495_978_707e11;
+/// Solar mass in kilograms
+pub const SOLAR_MASS: f64 = 1.988_47e30;
+/// Julian year in seconds
+pub const YEAR: f64 = 365.25 * 86_400.0;
+/// Kiloparsec in metres
+pub const KPC: f64 = 3.085_677_581_491_367e19;
+
+/// Units of length, mass and time, each given in SI units
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct UnitSystem {
+    /// Metres per unit of length
+    pub length: f64,
+    /// Kilograms per unit of mass
+    pub mass: f64,
+    /// Seconds per unit of time
+    pub time: f64,
+}
+
+impl UnitSystem {
+    pub const SI: UnitSystem = UnitSystem {
+        length: 1.0,
+        mass: 1.0,
+        time: 1.0,
+    };
+
+    /// AU, solar mass and year, where G is about 4π²
+    pub const ASTRONOMICAL: UnitSystem = UnitSystem {
+        length: AU,
+        mass: SOLAR_MASS,
+        time: YEAR,
+    };
+
+    /// Kiloparsec, 10^10 solar masses and gigayear
+    pub const GALACTIC: UnitSystem = UnitSystem {
+        length: KPC,
+        mass: 1e10 * SOLAR_MASS,
+        time: 1e9 * YEAR,
+    };
+
+    /// N-body units of the given length and mass in SI units, with the unit
+    /// of time picked so that G is 1
+    pub fn nbody(length: f64, mass: f64) -> UnitSystem {
+        UnitSystem {
+            length,
+            mass,
+            time: (length.powi(3) / (G * mass)).sqrt(),
+        }
+    }
+
+    /// Gravitational constant in these units, for `Simulation::g`
+    pub fn g(&self) -> f64 {
+        G * self.mass * self.time * self.time / self.length.powi(3)
+    }
+
+    /// Metres per second per unit of velocity
+    pub fn velocity(&self) -> f64 {
+        self.length / self.time
+    }
+
+    /// Metres per second squared per unit of acceleration
+    pub fn acceleration(&self) -> f64 {
+        self.length / (self.time * self.time)
+    }
+
+    /// Joules per unit of energy
+    pub fn energy(&self) -> f64 {
+        self.mass * self.velocity() * self.velocity()
+    }
+
+    /// `b`, given in these units, in the units of `to`
+    pub fn convert(&self, b: &Body, to: &UnitSystem) -> Body {
+        let length = self.length / to.length;
+        Body {
+            id: b.id,
+            pos: b.pos * length,
+            vel: b.vel * (self.velocity() / to.velocity()),
+            acc: b.acc * (self.acceleration() / to.acceleration()),
+            mass: b.mass * (self.mass / to.mass),
+            softening: b.softening.map(|s| s * length),
+            origin: b.origin,
+        }
+    }
+
+    /// Every body of `bodies`, given in these units, in the units of `to`
+    pub fn convert_all(&self, bodies: &[Body], to: &UnitSystem) -> Vec<Body> {
+        bodies.iter().map(|b| self.convert(b, to)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vector3;
+
+    #[test]
+    fn test_gravitational_constants() {
+        assert_eq!(UnitSystem::SI.g(), G);
+        let four_pi2 = 4.0 * std::f64::consts::PI * std::f64::consts::PI;
+        assert!((UnitSystem::ASTRONOMICAL.g() / four_pi2 - 1.0).abs() < 1e-3);
+        // 4.30091e-6 kpc (km/s)² / Msun, with 1.02271 km/s to a kpc per Gyr
+        assert!((UnitSystem::GALACTIC.g() / 4.4985e4 - 1.0).abs() < 1e-3);
+        assert!((UnitSystem::nbody(KPC, 1e10 * SOLAR_MASS).g() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_convert_round_trip() {
+        let earth = Body {
+            softening: Some(1e-3),
+            ..Body::new(
+                3,
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(0.0, 2.0 * std::f64::consts::PI, 0.0),
+                3.0e-6,
+            )
+        };
+        let si = UnitSystem::ASTRONOMICAL.convert(&earth, &UnitSystem::SI);
+        assert_eq!(si.pos.x, AU);
+        // Earth moves at about 29.8 km/s
+        assert!((si.vel.y - 2.98e4).abs() < 100.0);
+        let back = UnitSystem::SI.convert(&si, &UnitSystem::ASTRONOMICAL);
+        let diff = back.vel - earth.vel;
+        assert!(diff.dot(diff).sqrt() < 1e-12);
+        assert!((back.mass / earth.mass - 1.0).abs() < 1e-12);
+    }
+}
@@ -1,7 +1,5 @@
 use std::ops::{Add, Div, Mul, Sub};
 
-const EPSILON: f64 = 0.001;
-
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct Vector3 {
     pub x: f64,
@@ -23,18 +21,24 @@ impl Vector3 {
     }
 
     pub fn distance(&self, other: Self) -> f64 {
-        ((self.x - other.x).powi(2)
-            + (self.y - other.y).powi(2)
-            + (self.z - other.z).powi(2)
-            + EPSILON)
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2))
             .sqrt()
     }
 
     pub fn distance_2(&self, other: Self) -> f64 {
-        (self.x - other.x).powi(2)
-            + (self.y - other.y).powi(2)
-            + (self.z - other.z).powi(2)
-            + EPSILON
+        (self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2)
+    }
+
+    pub fn dot(&self, other: Self) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Self) -> Self {
+        Vector3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
     }
 
     pub fn normal_vector_between(&self, other: Self) -> Self {